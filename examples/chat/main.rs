@@ -0,0 +1,1184 @@
+mod outbox;
+mod protocol;
+mod ws;
+
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use dashmap::mapref::entry::Entry;
+use dashmap::DashMap;
+use futures::{Sink, SinkExt, StreamExt};
+use outbox::{Outbox, SlowConsumer};
+use protocol::{Mode, Request};
+use serde::Serialize;
+use socket2::{SockRef, TcpKeepalive};
+use std::collections::{HashSet, VecDeque};
+use std::fmt::Display;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::ops::ControlFlow;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::task::JoinHandle;
+use tokio::time::{interval_at, timeout, Instant, MissedTickBehavior};
+use tokio_util::codec::{Framed, LinesCodec};
+use tracing::level_filters::LevelFilter;
+use tracing::{info, warn};
+use tracing_subscriber::fmt::Layer;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::Layer as _;
+
+const WS_ADDR: &str = "0.0.0.0:8081";
+const DEFAULT_ROOM: &str = "lobby";
+const MAX_ROOM_NAME_LEN: usize = 32;
+const MAX_USERNAME_LEN: usize = 16;
+/// Messages kept per room.
+const HISTORY_SIZE: usize = 500;
+/// Messages replayed to a peer joining a room.
+const REPLAY_SIZE: usize = 20;
+/// Events queued per peer before its `SlowConsumer` policy applies.
+const OUTBOX_SIZE: usize = 1024;
+/// Peers that send nothing for this long are disconnected.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+/// How often quiet connections are probed, with TCP keepalive or WebSocket pings.
+const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Identifies a connection, assigned by the server when it claims a username.
+type PeerId = u64;
+
+#[derive(Debug)]
+struct Peer {
+    username: String,
+    room: String,
+}
+
+impl Peer {
+    fn new(username: String) -> Self {
+        Self {
+            username,
+            room: DEFAULT_ROOM.to_string(),
+        }
+    }
+}
+
+/// A logged in connection. Dropping it removes the peer and stops its writer, so that happens
+/// however the connection ends: quit, EOF, read or write errors, timeouts or a panic.
+#[derive(Debug)]
+struct Session {
+    state: Arc<State>,
+    id: PeerId,
+    peer: Peer,
+    writer: JoinHandle<()>,
+}
+
+/// A username reserved by a connection that is still logging in. Dropping it releases the
+/// name, unless `State::add` took it over for the session.
+#[derive(Debug)]
+struct Claim {
+    state: Arc<State>,
+    id: PeerId,
+    raddr: SocketAddr,
+    username: Option<String>,
+}
+
+#[derive(Debug)]
+struct PeerHandle {
+    /// Only for logging, the TCP and WebSocket listeners can see the same address.
+    raddr: SocketAddr,
+    username: String,
+    outbox: Arc<Outbox>,
+}
+
+/// Sent to TCP peers as text, or as JSON in an `Envelope` to json mode and WebSocket peers.
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum Message {
+    /// Sent to a peer once it's logged in.
+    Welcome {
+        username: String,
+        room: String,
+    },
+    UserJoined {
+        username: String,
+        room: String,
+    },
+    UserLeft {
+        username: String,
+        room: String,
+    },
+    Chat {
+        sender: String,
+        content: String,
+    },
+    Renamed {
+        old: String,
+        new: String,
+    },
+    /// A private message, only sent to its recipient.
+    Direct {
+        sender: String,
+        content: String,
+    },
+    /// A reply of the server to a single peer.
+    Info {
+        text: String,
+    },
+    /// An earlier message of the room, replayed to a peer.
+    History {
+        at: DateTime<Utc>,
+        message: Arc<Message>,
+    },
+}
+
+/// A message on its way to peers, with when and in which room it happened.
+#[derive(Debug)]
+struct Event {
+    at: DateTime<Utc>,
+    room: Option<String>,
+    message: Arc<Message>,
+}
+
+#[derive(Debug, PartialEq)]
+enum Command<'a> {
+    Quit,
+    Join(&'a str),
+    Leave,
+    Rooms,
+    Who,
+    Msg { to: &'a str, content: &'a str },
+    Nick(&'a str),
+    History(Option<usize>),
+    Lag,
+    Slow(SlowConsumer),
+}
+
+#[derive(Debug, Default)]
+struct State {
+    /// What to do with peers that can't keep up unless they chose otherwise with `/slow`,
+    /// set by `CHAT_SLOW_CONSUMER`.
+    policy: SlowConsumer,
+    /// The id of the next connection that claims a username.
+    next_id: AtomicU64,
+    peers: DashMap<PeerId, PeerHandle>,
+    /// Finds the peer of a username for direct messages.
+    usernames: DashMap<String, PeerId>,
+    /// Members of each room, a room exists as long as it has any.
+    rooms: DashMap<String, HashSet<PeerId>>,
+    /// Recent messages of each room, dropped with the room once it empties.
+    history: DashMap<String, VecDeque<Arc<Event>>>,
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let layer = Layer::new().with_ansi(true).with_filter(LevelFilter::DEBUG);
+    tracing_subscriber::registry().with(layer).init();
+
+    // console_subscriber::init();
+
+    let addr = "0.0.0.0:8080";
+    let listener = TcpListener::bind(addr).await?;
+    info!("Listening on {}", addr);
+
+    let policy = match std::env::var("CHAT_SLOW_CONSUMER") {
+        Ok(policy) => policy.parse()?,
+        Err(_) => SlowConsumer::default(),
+    };
+    info!("Slow consumers: {}", policy);
+    let state = Arc::new(State {
+        policy,
+        ..Default::default()
+    });
+
+    let ws_listener = TcpListener::bind(WS_ADDR).await?;
+    info!("Serving WebSocket clients on http://{}", WS_ADDR);
+    let router = ws::router(Arc::clone(&state)).into_make_service_with_connect_info::<SocketAddr>();
+    tokio::spawn(async move {
+        if let Err(e) = axum::serve(ws_listener, router).await {
+            warn!("WebSocket server failed: {}", e);
+        }
+    });
+
+    loop {
+        let (stream, raddr) = listener.accept().await?;
+        info!("Accepted connection from: {}", raddr);
+        if let Err(e) = keepalive(&stream) {
+            warn!("Failed to enable keepalive for {}: {}", raddr, e);
+        }
+        let state_clone = Arc::clone(&state);
+        tokio::spawn(async move {
+            if let Err(e) = handle_client(stream, raddr, state_clone).await {
+                warn!("Failed to handle client {}: {}", raddr, e);
+            }
+        });
+    }
+}
+
+async fn handle_client(stream: TcpStream, raddr: SocketAddr, state: Arc<State>) -> Result<()> {
+    let mut stream = Framed::new(stream, LinesCodec::new());
+    stream.send("Please enter your username:").await?;
+
+    let mut mode = Mode::Text;
+    let claim = loop {
+        let line = match timeout(IDLE_TIMEOUT, stream.next()).await {
+            Ok(Some(Ok(line))) => line,
+            Ok(Some(Err(e))) => return Err(e.into()),
+            Ok(None) => return Ok(()),
+            Err(_) => {
+                info!("Client {} timed out before logging in", raddr);
+                return Ok(());
+            }
+        };
+        let line = line.trim();
+        // logging in with JSON switches to json mode
+        let username = if line.starts_with('{') {
+            mode = Mode::Json;
+            match Request::parse(line) {
+                Ok(Request::Login { username }) => username,
+                Ok(_) => {
+                    prompt(&mut stream, mode, "Please log in first".into()).await?;
+                    continue;
+                }
+                Err(e) => {
+                    prompt(&mut stream, mode, e).await?;
+                    continue;
+                }
+            }
+        } else {
+            line.to_string()
+        };
+
+        if let Err(e) = validate_username(&username) {
+            prompt(&mut stream, mode, format!("{}, please try again:", e)).await?;
+        } else if let Some(claim) = State::claim(&state, &username, raddr) {
+            break claim;
+        } else {
+            let text = format!("{} is taken, please try another one:", username);
+            prompt(&mut stream, mode, text).await?;
+        }
+    };
+
+    let json = Arc::new(AtomicBool::new(mode == Mode::Json));
+    let (sender, mut receiver) = stream.split();
+    let id = claim.id;
+    let (peer, outbox) = state.add(claim);
+    let writer_json = Arc::clone(&json);
+    let encode = move |event: &Event| {
+        if writer_json.load(Ordering::Relaxed) {
+            event.to_json()
+        } else {
+            event.message.to_string()
+        }
+    };
+    // TCP keepalive probes these connections, see `keepalive`
+    let writer = tokio::spawn(pump(Arc::clone(&outbox), sender, encode, None));
+    let mut session = Session::new(Arc::clone(&state), id, peer, writer);
+    state.enter(id, &session.peer);
+    let peer = &mut session.peer;
+
+    loop {
+        let read = async {
+            match receiver.next().await? {
+                Ok(line) => Some(line),
+                Err(e) => {
+                    warn!("Failed to read line: {}", e);
+                    None
+                }
+            }
+        };
+        let Some(line) = next_input(&outbox, raddr, read).await else {
+            break;
+        };
+
+        if line.is_empty() {
+            continue;
+        }
+
+        let proto = if json.load(Ordering::Relaxed) {
+            match Request::parse(&line) {
+                Ok(Request::Proto { mode }) => Some(Ok(mode)),
+                Ok(request) => {
+                    if state.request(id, peer, request).is_break() {
+                        break;
+                    }
+                    None
+                }
+                Err(e) => {
+                    state.send(id, Message::info(e));
+                    None
+                }
+            }
+        } else if let Some(mode) = line.strip_prefix("/proto") {
+            Some(mode.trim().parse().map_err(|_| "Usage: /proto text|json"))
+        } else {
+            if state.line(id, peer, &line).is_break() {
+                break;
+            }
+            None
+        };
+
+        match proto {
+            Some(Ok(mode)) => {
+                json.store(mode == Mode::Json, Ordering::Relaxed);
+                let info = Message::info(format!("Switched to {} mode", mode));
+                state.send(id, info);
+            }
+            Some(Err(e)) => state.send(id, Message::info(e)),
+            None => {}
+        }
+    }
+
+    info!("Client {} disconnected", raddr);
+    Ok(())
+}
+
+/// Writes the events of a peer's outbox to its connection until the outbox is closed. A
+/// failed send closes the outbox, so the reader stops too. Peers with a `keepalive` get it
+/// sent every `KEEPALIVE_INTERVAL`.
+async fn pump<S, T>(
+    outbox: Arc<Outbox>,
+    mut sink: S,
+    encode: impl Fn(&Event) -> T,
+    keepalive: Option<T>,
+) where
+    S: Sink<T> + Unpin,
+    S::Error: Display,
+    T: Clone,
+{
+    let mut ping = interval_at(Instant::now() + KEEPALIVE_INTERVAL, KEEPALIVE_INTERVAL);
+    ping.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    loop {
+        let item = tokio::select! {
+            event = outbox.pop() => match event {
+                Some(event) => encode(&event),
+                None => break,
+            },
+            _ = ping.tick(), if keepalive.is_some() => {
+                keepalive.clone().expect("only ticks with a keepalive")
+            }
+        };
+        if let Err(e) = sink.send(item).await {
+            warn!("Failed to send message: {}", e);
+            // let the reader know the connection is gone
+            outbox.close();
+            break;
+        }
+    }
+}
+
+/// What a logged in peer sent next, read by `read`. `None` once the connection is gone,
+/// the peer was idle for `IDLE_TIMEOUT`, or its outbox was closed because it was too slow
+/// or the writer failed.
+async fn next_input<T>(
+    outbox: &Outbox,
+    raddr: SocketAddr,
+    read: impl Future<Output = Option<T>>,
+) -> Option<T> {
+    tokio::select! {
+        input = timeout(IDLE_TIMEOUT, read) => input.unwrap_or_else(|_| {
+            info!("Client {} was idle for {:?}", raddr, IDLE_TIMEOUT);
+            None
+        }),
+        _ = outbox.closed() => None,
+    }
+}
+
+/// Replies to a TCP peer that isn't logged in yet.
+async fn prompt(
+    stream: &mut Framed<TcpStream, LinesCodec>,
+    mode: Mode,
+    text: String,
+) -> Result<()> {
+    let line = match mode {
+        Mode::Text => text,
+        Mode::Json => Event::new(None, Arc::new(Message::info(text))).to_json(),
+    };
+    stream.send(line).await?;
+    Ok(())
+}
+
+/// Lets the OS probe quiet connections, so half-open ones fail their next read.
+fn keepalive(stream: &TcpStream) -> std::io::Result<()> {
+    let keepalive = TcpKeepalive::new()
+        .with_time(KEEPALIVE_INTERVAL)
+        .with_interval(KEEPALIVE_INTERVAL);
+    SockRef::from(stream).set_tcp_keepalive(&keepalive)
+}
+
+impl Session {
+    fn new(state: Arc<State>, id: PeerId, peer: Peer, writer: JoinHandle<()>) -> Self {
+        Self {
+            state,
+            id,
+            peer,
+            writer,
+        }
+    }
+}
+
+impl Drop for Claim {
+    fn drop(&mut self) {
+        if let Some(username) = &self.username {
+            self.state.release(username, self.id);
+        }
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        // the writer may be stuck on a full socket
+        self.writer.abort();
+        self.state.disconnect(self.id, &self.peer);
+    }
+}
+
+impl State {
+    /// Registers the peer of a claimed username, the transport writes what arrives in the
+    /// returned outbox, and drops the peer once it's closed.
+    fn add(&self, mut claim: Claim) -> (Peer, Arc<Outbox>) {
+        // the session releases the name from now on
+        let username = claim.username.take().unwrap_or_default();
+        let outbox = Arc::new(Outbox::new(OUTBOX_SIZE, self.policy));
+        self.peers.insert(
+            claim.id,
+            PeerHandle {
+                raddr: claim.raddr,
+                username: username.clone(),
+                outbox: Arc::clone(&outbox),
+            },
+        );
+        (Peer::new(username), outbox)
+    }
+
+    /// Puts a new peer in its room and tells the room.
+    fn enter(&self, id: PeerId, peer: &Peer) {
+        let welcome = Message::Welcome {
+            username: peer.username.clone(),
+            room: peer.room.clone(),
+        };
+        self.send(id, welcome);
+        self.join(id, &peer.room);
+        self.replay(id, &peer.room, REPLAY_SIZE);
+        self.broadcast(id, &peer.room, Arc::new(Message::user_joined(peer)));
+    }
+
+    fn chat(&self, id: PeerId, peer: &Peer, content: impl Into<String>) {
+        let message = Arc::new(Message::chat(&peer.username, content));
+        self.broadcast(id, &peer.room, message);
+    }
+
+    /// Handles a line of a text mode peer, breaks once the peer quit.
+    fn line(&self, id: PeerId, peer: &mut Peer, line: &str) -> ControlFlow<()> {
+        if !line.starts_with('/') {
+            self.chat(id, peer, line);
+            return ControlFlow::Continue(());
+        }
+        match Command::parse(line) {
+            Ok(command) => return self.execute(id, peer, command),
+            Err(e) => {
+                warn!("Invalid command from {}: {}", peer.username, line);
+                self.send(id, Message::info(e));
+            }
+        }
+        ControlFlow::Continue(())
+    }
+
+    /// Handles a request of a JSON peer, breaks once the peer quit.
+    fn request(&self, id: PeerId, peer: &mut Peer, request: Request) -> ControlFlow<()> {
+        match request.command() {
+            Some(Ok(command)) => return self.execute(id, peer, command),
+            Some(Err(e)) => self.send(id, Message::info(e)),
+            None => match request {
+                Request::Chat { content } if !content.is_empty() => self.chat(id, peer, content),
+                Request::Login { .. } => self.send(id, Message::info("Already logged in")),
+                Request::Proto { .. } => {
+                    let info = Message::info("This connection only speaks json");
+                    self.send(id, info)
+                }
+                _ => {}
+            },
+        }
+        ControlFlow::Continue(())
+    }
+
+    /// Runs a command of `peer`, breaks once the peer quit.
+    fn execute(&self, id: PeerId, peer: &mut Peer, command: Command<'_>) -> ControlFlow<()> {
+        match command {
+            Command::Quit => {
+                self.disconnect(id, peer);
+                return ControlFlow::Break(());
+            }
+            Command::Join(room) => self.switch_room(id, peer, room),
+            Command::Leave if peer.room == DEFAULT_ROOM => {
+                self.send(id, Message::info("You are already in the lobby"))
+            }
+            Command::Leave => self.switch_room(id, peer, DEFAULT_ROOM),
+            Command::Rooms => self.send(id, self.list_rooms()),
+            Command::Who => self.send(id, self.list_members(&peer.room)),
+            Command::Msg { to, content } => self.direct(id, &peer.username, to, content),
+            Command::Nick(new) => self.rename(id, peer, new),
+            Command::Lag => self.send(id, self.list_lag(&peer.room)),
+            Command::Slow(policy) => {
+                if let Some(peer) = self.peers.get(&id) {
+                    peer.outbox.set_policy(policy);
+                }
+                self.send(
+                    id,
+                    Message::info(format!("Slow consumer policy: {}", policy)),
+                )
+            }
+            Command::History(n) => {
+                let n = n.unwrap_or(REPLAY_SIZE).min(HISTORY_SIZE);
+                if !self.replay(id, &peer.room, n) {
+                    let info = Message::info(format!("No history in #{}", peer.room));
+                    self.send(id, info);
+                }
+            }
+        }
+        ControlFlow::Continue(())
+    }
+
+    /// Reserves `username` for a connection logging in, `None` if someone else has it.
+    /// The connection gets its id here.
+    fn claim(state: &Arc<Self>, username: &str, raddr: SocketAddr) -> Option<Claim> {
+        let id = state.next_id.fetch_add(1, Ordering::Relaxed);
+        state.reserve(username, id).then(|| Claim {
+            state: Arc::clone(state),
+            id,
+            raddr,
+            username: Some(username.to_string()),
+        })
+    }
+
+    /// Reserves `username` for `id`, false if someone else has it.
+    fn reserve(&self, username: &str, id: PeerId) -> bool {
+        match self.usernames.entry(username.to_string()) {
+            Entry::Occupied(_) => false,
+            Entry::Vacant(entry) => {
+                entry.insert(id);
+                true
+            }
+        }
+    }
+
+    /// Claims `new` before releasing the old name, so no one can take either in between.
+    fn rename(&self, id: PeerId, peer: &mut Peer, new: &str) {
+        if peer.username == new {
+            let info = Message::info(format!("You are already {}", new));
+            self.send(id, info);
+            return;
+        }
+        if !self.reserve(new, id) {
+            let info = Message::info(format!("{} is taken", new));
+            self.send(id, info);
+            return;
+        }
+        self.release(&peer.username, id);
+        if let Some(mut handle) = self.peers.get_mut(&id) {
+            handle.username = new.to_string();
+        }
+
+        let old = std::mem::replace(&mut peer.username, new.to_string());
+        let message = Arc::new(Message::renamed(old, new));
+        self.broadcast(id, &peer.room, Arc::clone(&message));
+        self.deliver(id, Arc::new(Event::new(Some(&peer.room), message)));
+    }
+
+    /// Frees `username` if `id` still has it.
+    fn release(&self, username: &str, id: PeerId) {
+        self.usernames
+            .remove_if(username, |_, peer_id| *peer_id == id);
+    }
+
+    /// Removes a peer that quit or can't be reached anymore and tells its room, only the first
+    /// call for a peer does anything.
+    fn disconnect(&self, id: PeerId, peer: &Peer) {
+        if self.remove(id, &peer.room) {
+            self.broadcast(id, &peer.room, Arc::new(Message::user_left(peer)));
+        }
+    }
+
+    /// Forgets a peer, returns whether it was still there.
+    fn remove(&self, id: PeerId, room: &str) -> bool {
+        let Some((_, peer)) = self.peers.remove(&id) else {
+            return false;
+        };
+        self.release(&peer.username, id);
+        peer.outbox.close();
+        self.leave(id, room);
+        true
+    }
+
+    /// Adds `id` to `room`, creating the room on its first member.
+    fn join(&self, id: PeerId, room: &str) {
+        self.rooms.entry(room.to_string()).or_default().insert(id);
+    }
+
+    /// Removes `id` from `room`, dropping the room and its history once it's empty.
+    fn leave(&self, id: PeerId, room: &str) {
+        self.rooms.remove_if_mut(room, |_, members| {
+            members.remove(&id);
+            if !members.is_empty() {
+                return false;
+            }
+            self.history.remove(room);
+            true
+        });
+    }
+
+    fn switch_room(&self, id: PeerId, peer: &mut Peer, room: &str) {
+        if peer.room == room {
+            let info = Message::info(format!("You are already in #{}", room));
+            self.send(id, info);
+            return;
+        }
+
+        self.leave(id, &peer.room);
+        self.broadcast(id, &peer.room, Arc::new(Message::user_left(peer)));
+
+        peer.room = room.to_string();
+        self.join(id, room);
+        self.replay(id, room, REPLAY_SIZE);
+        self.broadcast(id, room, Arc::new(Message::user_joined(peer)));
+        self.send(id, self.list_members(room));
+    }
+
+    /// Keeps `event` in the history of `room`, dropping the oldest one when it's full. Rooms
+    /// that are gone have no history, e.g. for the goodbye of their last member.
+    fn record(&self, room: &str, event: &Arc<Event>) {
+        // held so `leave` can't drop the room in between, it locks in the same order
+        let Some(_room) = self.rooms.get(room) else {
+            return;
+        };
+        let mut history = self.history.entry(room.to_string()).or_default();
+        if history.len() == HISTORY_SIZE {
+            history.pop_front();
+        }
+        history.push_back(Arc::clone(event));
+    }
+
+    /// The last `n` messages of `room`, oldest first.
+    fn recent(&self, room: &str, n: usize) -> Vec<Message> {
+        let Some(history) = self.history.get(room) else {
+            return vec![];
+        };
+        history
+            .iter()
+            .skip(history.len().saturating_sub(n))
+            .map(|event| Message::History {
+                at: event.at,
+                message: Arc::clone(&event.message),
+            })
+            .collect()
+    }
+
+    /// Sends the last `n` messages of `room` to `id`, returns whether there were any.
+    fn replay(&self, id: PeerId, room: &str, n: usize) -> bool {
+        let messages = self.recent(room, n);
+        let replayed = !messages.is_empty();
+        for message in messages {
+            let event = Event::new(Some(room), Arc::new(message));
+            self.deliver(id, Arc::new(event));
+        }
+        replayed
+    }
+
+    fn list_rooms(&self) -> Message {
+        let mut rooms: Vec<_> = self
+            .rooms
+            .iter()
+            .map(|room| format!("#{} ({})", room.key(), room.value().len()))
+            .collect();
+        rooms.sort();
+        Message::info(format!("Rooms: {}", rooms.join(", ")))
+    }
+
+    fn list_members(&self, room: &str) -> Message {
+        let mut members: Vec<_> = self
+            .members(room)
+            .into_iter()
+            .filter_map(|peer_id| self.peers.get(&peer_id).map(|peer| peer.username.clone()))
+            .collect();
+        members.sort();
+        Message::info(format!("Members of #{}: {}", room, members.join(", ")))
+    }
+
+    fn list_lag(&self, room: &str) -> Message {
+        let mut lags: Vec<_> = self
+            .members(room)
+            .into_iter()
+            .filter_map(|peer_id| {
+                let peer = self.peers.get(&peer_id)?;
+                let lag = peer.outbox.lag();
+                Some(format!(
+                    "{} {} queued (peak {}, {} dropped, {})",
+                    peer.username,
+                    lag.queued,
+                    lag.peak,
+                    lag.dropped,
+                    peer.outbox.policy()
+                ))
+            })
+            .collect();
+        lags.sort();
+        Message::info(format!("Lag in #{}: {}", room, lags.join(", ")))
+    }
+
+    fn members(&self, room: &str) -> Vec<PeerId> {
+        self.rooms
+            .get(room)
+            .map(|members| members.iter().copied().collect())
+            .unwrap_or_default()
+    }
+
+    /// Sends to a single peer.
+    fn send(&self, id: PeerId, message: Message) {
+        let event = Event::new(None, Arc::new(message));
+        self.deliver(id, Arc::new(event))
+    }
+
+    /// Queues `event` for `id` without waiting, a full outbox is handled by the policy.
+    fn deliver(&self, id: PeerId, event: Arc<Event>) {
+        // clone the outbox so no map guard is held while pushing
+        let Some((raddr, outbox)) = self
+            .peers
+            .get(&id)
+            .map(|peer| (peer.raddr, Arc::clone(&peer.outbox)))
+        else {
+            return;
+        };
+        if !outbox.push(event) {
+            // the peer's connection task sees the closed outbox and cleans up
+            warn!("Disconnecting slow client {}", raddr);
+            outbox.close();
+        }
+    }
+
+    /// Sends `content` to the user named `to` only, or tells `id` there is no such user.
+    fn direct(&self, id: PeerId, sender: &str, to: &str, content: &str) {
+        let Some(peer_id) = self.usernames.get(to).map(|peer_id| *peer_id) else {
+            let info = Message::info(format!("No such user: {}", to));
+            self.send(id, info);
+            return;
+        };
+        self.send(peer_id, Message::direct(sender, content));
+    }
+
+    /// Sends to everyone in `room` but `id`, and keeps it in the room's history.
+    fn broadcast(&self, id: PeerId, room: &str, message: Arc<Message>) {
+        let event = Arc::new(Event::new(Some(room), message));
+        self.record(room, &event);
+        // collect first so no guard on `rooms` is held while delivering
+        for peer_id in self.members(room) {
+            if peer_id != id {
+                self.deliver(peer_id, Arc::clone(&event));
+            }
+        }
+    }
+}
+
+impl Event {
+    fn new(room: Option<&str>, message: Arc<Message>) -> Self {
+        Self {
+            at: Utc::now(),
+            room: room.map(|room| room.to_string()),
+            message,
+        }
+    }
+}
+
+impl Message {
+    fn user_joined(peer: &Peer) -> Self {
+        Self::UserJoined {
+            username: peer.username.clone(),
+            room: peer.room.clone(),
+        }
+    }
+
+    fn user_left(peer: &Peer) -> Self {
+        Self::UserLeft {
+            username: peer.username.clone(),
+            room: peer.room.clone(),
+        }
+    }
+
+    fn chat(sender: impl Into<String>, content: impl Into<String>) -> Self {
+        Self::Chat {
+            sender: sender.into(),
+            content: content.into(),
+        }
+    }
+
+    fn renamed(old: impl Into<String>, new: impl Into<String>) -> Self {
+        Self::Renamed {
+            old: old.into(),
+            new: new.into(),
+        }
+    }
+
+    fn direct(sender: impl Into<String>, content: impl Into<String>) -> Self {
+        Self::Direct {
+            sender: sender.into(),
+            content: content.into(),
+        }
+    }
+
+    fn info(text: impl Into<String>) -> Self {
+        Self::Info { text: text.into() }
+    }
+}
+
+impl Display for Message {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Welcome { username, room } => {
+                write!(f, "[Welcome {}, you are in #{}]", username, room)
+            }
+            Self::UserJoined { username, room } => write!(f, "[{} joined #{}]", username, room),
+            Self::UserLeft { username, room } => write!(f, "[{} left #{}]", username, room),
+            Self::Chat { sender, content } => write!(f, "{}: {}", sender, content),
+            Self::Renamed { old, new } => write!(f, "[{} is now known as {}]", old, new),
+            Self::Direct { sender, content } => write!(f, "{} (private): {}", sender, content),
+            Self::Info { text } => write!(f, "[{}]", text),
+            Self::History { at, message } => write!(f, "[{}] {}", at.format("%H:%M:%S"), message),
+        }
+    }
+}
+
+impl<'a> Command<'a> {
+    /// Parses a line starting with `/`, the error is the reply for the user.
+    fn parse(line: &'a str) -> Result<Self, String> {
+        let (name, args) = match line.split_once(' ') {
+            Some((name, args)) => (name, args.trim()),
+            None => (line, ""),
+        };
+        match (name, args) {
+            ("/quit", "") => Ok(Self::Quit),
+            ("/join", "") => Err("Usage: /join <room>".into()),
+            ("/join", room) => validate_room(room).map(|_| Self::Join(room)),
+            ("/leave", "") => Ok(Self::Leave),
+            ("/rooms", "") => Ok(Self::Rooms),
+            ("/who", "") => Ok(Self::Who),
+            ("/msg", args) => match args.split_once(' ') {
+                Some((to, content)) if !content.trim().is_empty() => Ok(Self::Msg {
+                    to,
+                    content: content.trim(),
+                }),
+                _ => Err("Usage: /msg <username> <text>".into()),
+            },
+            ("/nick", "") => Err("Usage: /nick <username>".into()),
+            ("/nick", new) => validate_username(new).map(|_| Self::Nick(new)),
+            ("/lag", "") => Ok(Self::Lag),
+            ("/slow", policy) => policy
+                .parse()
+                .map(Self::Slow)
+                .map_err(|_| "Usage: /slow <drop-oldest|drop-newest|disconnect>".into()),
+            ("/history", "") => Ok(Self::History(None)),
+            ("/history", n) => n
+                .parse()
+                .map(|n| Self::History(Some(n)))
+                .map_err(|_| "Usage: /history [n]".into()),
+            _ => Err(format!("Unknown command: {}", line)),
+        }
+    }
+}
+
+fn validate_room(room: &str) -> Result<(), String> {
+    if is_valid_name(room, MAX_ROOM_NAME_LEN) {
+        Ok(())
+    } else {
+        Err(format!(
+            "Room names are up to {} letters, digits, '-' or '_'",
+            MAX_ROOM_NAME_LEN
+        ))
+    }
+}
+
+fn validate_username(username: &str) -> Result<(), String> {
+    if is_valid_name(username, MAX_USERNAME_LEN) {
+        Ok(())
+    } else {
+        Err(format!(
+            "Usernames are up to {} letters, digits, '-' or '_'",
+            MAX_USERNAME_LEN
+        ))
+    }
+}
+
+fn is_valid_name(name: &str, max_len: usize) -> bool {
+    !name.is_empty()
+        && name.len() <= max_len
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A logged in peer that isn't in any room yet.
+    fn login(state: &Arc<State>, username: &str) -> (PeerId, Peer, Arc<Outbox>) {
+        let claim = State::claim(state, username, "127.0.0.1:1".parse().unwrap()).unwrap();
+        let id = claim.id;
+        let (peer, outbox) = state.add(claim);
+        (id, peer, outbox)
+    }
+
+    /// The queued messages as text, replayed ones without their time.
+    async fn drain(outbox: &Outbox) -> Vec<String> {
+        let mut lines = vec![];
+        while outbox.lag().queued > 0 {
+            let event = outbox.pop().await.unwrap();
+            lines.push(match &*event.message {
+                Message::History { message, .. } => message.to_string(),
+                message => message.to_string(),
+            });
+        }
+        lines
+    }
+
+    #[test]
+    fn command_should_parse() {
+        assert_eq!(Command::parse("/quit"), Ok(Command::Quit));
+        assert_eq!(Command::parse("/join rust"), Ok(Command::Join("rust")));
+        assert_eq!(Command::parse("/leave"), Ok(Command::Leave));
+        assert_eq!(Command::parse("/rooms"), Ok(Command::Rooms));
+        assert_eq!(Command::parse("/who"), Ok(Command::Who));
+        assert_eq!(
+            Command::parse("/msg bob  hi there"),
+            Ok(Command::Msg {
+                to: "bob",
+                content: "hi there"
+            })
+        );
+        assert!(Command::parse("/msg bob").is_err());
+        assert_eq!(Command::parse("/nick ally"), Ok(Command::Nick("ally")));
+        assert!(Command::parse("/nick al ly").is_err());
+        assert_eq!(Command::parse("/history"), Ok(Command::History(None)));
+        assert_eq!(Command::parse("/history 5"), Ok(Command::History(Some(5))));
+        assert!(Command::parse("/history all").is_err());
+        assert_eq!(
+            Command::parse("/slow disconnect"),
+            Ok(Command::Slow(SlowConsumer::Disconnect))
+        );
+        assert!(Command::parse("/slow").is_err());
+        assert!(Command::parse("/slow wait").is_err());
+        assert!(Command::parse("/join").is_err());
+        assert!(Command::parse("/join no spaces").is_err());
+        assert!(Command::parse("/quit now").is_err());
+        assert!(Command::parse("/dance").is_err());
+    }
+
+    #[test]
+    fn room_should_be_dropped_when_empty() {
+        let state = State::default();
+        let (a, b) = (1, 2);
+        state.join(a, "rust");
+        state.join(b, "rust");
+        state.leave(a, "rust");
+        assert_eq!(state.members("rust"), vec![b]);
+        state.leave(b, "rust");
+        assert!(!state.rooms.contains_key("rust"));
+    }
+
+    #[test]
+    fn username_should_be_valid_and_unique() {
+        assert!(validate_username("alice_1").is_ok());
+        assert!(validate_username("").is_err());
+        assert!(validate_username("bob smith").is_err());
+        assert!(validate_username(&"a".repeat(MAX_USERNAME_LEN + 1)).is_err());
+
+        let state = Arc::new(State::default());
+        let claim = State::claim(&state, "alice", "127.0.0.1:1".parse().unwrap());
+        assert!(claim.is_some());
+        assert!(State::claim(&state, "alice", "127.0.0.1:2".parse().unwrap()).is_none());
+
+        // a connection that drops before logging in gives the name back
+        drop(claim);
+        assert!(!state.usernames.contains_key("alice"));
+        assert!(State::claim(&state, "alice", "127.0.0.1:2".parse().unwrap()).is_some());
+    }
+
+    #[tokio::test]
+    async fn slow_should_change_only_own_policy() {
+        let state = Arc::new(State::default());
+        let (a, mut alice, alice_out) = login(&state, "alice");
+        let (_, _, bob_out) = login(&state, "bob");
+
+        let slow = Command::parse("/slow drop-newest").unwrap();
+        assert!(state.execute(a, &mut alice, slow).is_continue());
+        assert_eq!(
+            drain(&alice_out).await,
+            ["[Slow consumer policy: drop-newest]"]
+        );
+        assert_eq!(alice_out.policy(), SlowConsumer::DropNewest);
+        assert_eq!(bob_out.policy(), state.policy);
+    }
+
+    #[tokio::test]
+    async fn nick_should_rename_unless_taken() {
+        let state = Arc::new(State::default());
+        let (a, mut alice, alice_out) = login(&state, "alice");
+        let (b, bob, bob_out) = login(&state, "bob");
+        state.enter(a, &alice);
+        state.enter(b, &bob);
+        drain(&alice_out).await;
+        drain(&bob_out).await;
+
+        let nick = Command::parse("/nick bob").unwrap();
+        assert!(state.execute(a, &mut alice, nick).is_continue());
+        assert_eq!(drain(&alice_out).await, ["[bob is taken]"]);
+        assert_eq!(alice.username, "alice");
+        assert_eq!(state.usernames.get("bob").map(|id| *id), Some(b));
+
+        let nick = Command::parse("/nick ally").unwrap();
+        assert!(state.execute(a, &mut alice, nick).is_continue());
+        assert_eq!(alice.username, "ally");
+        assert_eq!(state.usernames.get("ally").map(|id| *id), Some(a));
+        assert_eq!(state.peers.get(&a).unwrap().username, "ally");
+        assert!(!state.usernames.contains_key("alice"));
+
+        let event = bob_out.pop().await.unwrap();
+        assert_eq!(event.room.as_deref(), Some(DEFAULT_ROOM));
+        assert!(matches!(
+            &*event.message,
+            Message::Renamed { old, new } if old == "alice" && new == "ally"
+        ));
+        assert_eq!(drain(&alice_out).await, ["[alice is now known as ally]"]);
+        // the old name is free for others
+        assert!(State::claim(&state, "alice", "127.0.0.1:2".parse().unwrap()).is_some());
+    }
+
+    #[tokio::test]
+    async fn direct_message_should_reach_only_its_target() {
+        let state = Arc::new(State::default());
+        let (a, mut alice, alice_out) = login(&state, "alice");
+        let (b, bob, bob_out) = login(&state, "bob");
+        let (c, carol, carol_out) = login(&state, "carol");
+        for (id, peer) in [(a, &alice), (b, &bob), (c, &carol)] {
+            state.enter(id, peer);
+        }
+        for outbox in [&alice_out, &bob_out, &carol_out] {
+            drain(outbox).await;
+        }
+
+        let msg = Command::parse("/msg bob psst").unwrap();
+        assert!(state.execute(a, &mut alice, msg).is_continue());
+        assert_eq!(drain(&bob_out).await, ["alice (private): psst"]);
+        assert!(drain(&alice_out).await.is_empty());
+        assert!(drain(&carol_out).await.is_empty());
+
+        let msg = Command::parse("/msg dave psst").unwrap();
+        assert!(state.execute(a, &mut alice, msg).is_continue());
+        assert_eq!(drain(&alice_out).await, ["[No such user: dave]"]);
+        assert!(drain(&bob_out).await.is_empty());
+    }
+
+    #[test]
+    fn history_should_keep_the_last_messages() {
+        let state = State::default();
+        state.join(1, "rust");
+        for i in 0..HISTORY_SIZE + 10 {
+            let message = Arc::new(Message::chat("alice", i.to_string()));
+            state.record("rust", &Arc::new(Event::new(Some("rust"), message)));
+        }
+        assert_eq!(state.history.get("rust").unwrap().len(), HISTORY_SIZE);
+
+        let recent = state.recent("rust", 2);
+        let contents: Vec<_> = recent
+            .iter()
+            .map(|m| match m {
+                Message::History { message, .. } => message.to_string(),
+                _ => unreachable!(),
+            })
+            .collect();
+        let last = HISTORY_SIZE + 9;
+        assert_eq!(
+            contents,
+            [format!("alice: {}", last - 1), format!("alice: {}", last)]
+        );
+        assert!(state.recent("go", 2).is_empty());
+    }
+
+    #[test]
+    fn history_should_be_dropped_with_room() {
+        let state = State::default();
+        let (a, b) = (1, 2);
+        state.join(a, "rust");
+        state.join(b, "rust");
+        state.broadcast(a, "rust", Arc::new(Message::chat("alice", "hi")));
+        state.leave(a, "rust");
+        assert_eq!(state.recent("rust", REPLAY_SIZE).len(), 1);
+
+        state.leave(b, "rust");
+        assert!(!state.history.contains_key("rust"));
+        // nothing is kept for a room that is gone
+        state.broadcast(b, "rust", Arc::new(Message::chat("bob", "bye")));
+        assert!(!state.history.contains_key("rust"));
+    }
+
+    #[tokio::test]
+    async fn late_joiner_should_get_recent_history() {
+        let state = Arc::new(State::default());
+        let (a, mut alice, _) = login(&state, "alice");
+        state.switch_room(a, &mut alice, "rust");
+        for i in 0..REPLAY_SIZE + 5 {
+            state.chat(a, &alice, i.to_string());
+        }
+
+        let (b, mut bob, outbox) = login(&state, "bob");
+        state.switch_room(b, &mut bob, "rust");
+        let lines = drain(&outbox).await;
+        let replayed: Vec<_> = (5..REPLAY_SIZE + 5)
+            .map(|i| format!("alice: {}", i))
+            .collect();
+        assert_eq!(lines[..REPLAY_SIZE], replayed[..]);
+        assert_eq!(lines[REPLAY_SIZE..], ["[Members of #rust: alice, bob]"]);
+    }
+
+    #[tokio::test]
+    async fn history_command_should_send_last_messages_oldest_first() {
+        let state = Arc::new(State::default());
+        let (a, mut alice, outbox) = login(&state, "alice");
+        state.switch_room(a, &mut alice, "rust");
+        for i in 0..5 {
+            state.chat(a, &alice, i.to_string());
+        }
+        drain(&outbox).await;
+
+        let history = Command::parse("/history 3").unwrap();
+        assert!(state.execute(a, &mut alice, history).is_continue());
+        assert_eq!(drain(&outbox).await, ["alice: 2", "alice: 3", "alice: 4"]);
+    }
+
+    #[tokio::test]
+    async fn dropped_session_should_remove_peer_once() {
+        let state = Arc::new(State::default());
+        // the same address may connect to both listeners
+        let raddr = "127.0.0.1:1".parse().unwrap();
+        let (alice, bob) = (
+            State::claim(&state, "alice", raddr).unwrap(),
+            State::claim(&state, "bob", raddr).unwrap(),
+        );
+        let (a, b) = (alice.id, bob.id);
+        assert_ne!(a, b);
+        let (alice, _) = state.add(alice);
+        let (_, outbox) = state.add(bob);
+        assert!(state.usernames.contains_key("alice"));
+        state.join(a, DEFAULT_ROOM);
+        state.join(b, DEFAULT_ROOM);
+
+        let session = Session::new(Arc::clone(&state), a, alice, tokio::spawn(async {}));
+        drop(session);
+        assert!(!state.peers.contains_key(&a));
+        assert!(!state.usernames.contains_key("alice"));
+        assert_eq!(state.members(DEFAULT_ROOM), vec![b]);
+
+        state.disconnect(a, &Peer::new("alice".into()));
+        assert_eq!(outbox.lag().queued, 1);
+        let event = outbox.pop().await.unwrap();
+        assert_eq!(event.message.to_string(), "[alice left #lobby]");
+    }
+}
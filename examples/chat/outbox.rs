@@ -0,0 +1,174 @@
+use crate::Event;
+use serde::Deserialize;
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use strum::{Display, EnumString};
+use tokio::sync::Notify;
+use tokio_util::sync::CancellationToken;
+
+/// What happens when a peer's outbox is full.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Display, EnumString, Deserialize)]
+#[strum(serialize_all = "kebab-case")]
+#[serde(rename_all = "kebab-case")]
+pub enum SlowConsumer {
+    /// Make room by dropping the oldest queued event.
+    #[default]
+    DropOldest,
+    /// Drop the new event.
+    DropNewest,
+    /// Disconnect the peer.
+    Disconnect,
+}
+
+/// Events waiting for a peer's writer. Pushing never waits, so a stalled peer only ever
+/// loses its own events.
+#[derive(Debug)]
+pub struct Outbox {
+    queue: Mutex<VecDeque<Arc<Event>>>,
+    notify: Notify,
+    closed: CancellationToken,
+    capacity: usize,
+    policy: Mutex<SlowConsumer>,
+    dropped: AtomicU64,
+    peak: AtomicUsize,
+}
+
+/// How far behind a peer is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Lag {
+    pub queued: usize,
+    pub peak: usize,
+    pub dropped: u64,
+}
+
+impl Outbox {
+    pub fn new(capacity: usize, policy: SlowConsumer) -> Self {
+        Self {
+            queue: Mutex::new(VecDeque::with_capacity(capacity.min(64))),
+            notify: Notify::new(),
+            closed: CancellationToken::new(),
+            capacity: capacity.max(1),
+            policy: Mutex::new(policy),
+            dropped: AtomicU64::new(0),
+            peak: AtomicUsize::new(0),
+        }
+    }
+
+    /// Queues `event`, false if the outbox is full and the peer should be disconnected.
+    pub fn push(&self, event: Arc<Event>) -> bool {
+        if self.closed.is_cancelled() {
+            return true;
+        }
+        let policy = self.policy();
+        let mut queue = self.queue.lock().unwrap();
+        if queue.len() >= self.capacity {
+            match policy {
+                SlowConsumer::DropOldest => {
+                    queue.pop_front();
+                }
+                SlowConsumer::DropNewest => {
+                    self.dropped.fetch_add(1, Ordering::Relaxed);
+                    return true;
+                }
+                SlowConsumer::Disconnect => return false,
+            }
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+        queue.push_back(event);
+        self.peak.fetch_max(queue.len(), Ordering::Relaxed);
+        drop(queue);
+
+        self.notify.notify_one();
+        true
+    }
+
+    /// The next event for the writer, `None` once the outbox is closed.
+    pub async fn pop(&self) -> Option<Arc<Event>> {
+        loop {
+            if self.closed.is_cancelled() {
+                return None;
+            }
+            if let Some(event) = self.queue.lock().unwrap().pop_front() {
+                return Some(event);
+            }
+            tokio::select! {
+                _ = self.notify.notified() => {}
+                _ = self.closed.cancelled() => return None,
+            }
+        }
+    }
+
+    /// Stops the writer, queued events are dropped.
+    pub fn close(&self) {
+        self.closed.cancel();
+    }
+
+    pub async fn closed(&self) {
+        self.closed.cancelled().await
+    }
+
+    pub fn policy(&self) -> SlowConsumer {
+        *self.policy.lock().unwrap()
+    }
+
+    /// Changes what happens once the outbox is full, queued events are kept.
+    pub fn set_policy(&self, policy: SlowConsumer) {
+        *self.policy.lock().unwrap() = policy;
+    }
+
+    pub fn lag(&self) -> Lag {
+        Lag {
+            queued: self.queue.lock().unwrap().len(),
+            peak: self.peak.load(Ordering::Relaxed),
+            dropped: self.dropped.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Message;
+
+    fn event(content: &str) -> Arc<Event> {
+        Arc::new(Event::new(None, Arc::new(Message::chat("bob", content))))
+    }
+
+    async fn drain(outbox: &Outbox) -> Vec<String> {
+        let mut contents = vec![];
+        while outbox.lag().queued > 0 {
+            contents.push(outbox.pop().await.unwrap().message.to_string());
+        }
+        contents
+    }
+
+    #[tokio::test]
+    async fn full_outbox_should_follow_policy() {
+        let outbox = Outbox::new(2, SlowConsumer::DropOldest);
+        assert!(["1", "2", "3"].iter().all(|c| outbox.push(event(c))));
+        let lag = outbox.lag();
+        assert_eq!((lag.queued, lag.peak, lag.dropped), (2, 2, 1));
+        assert_eq!(drain(&outbox).await, ["bob: 2", "bob: 3"]);
+
+        let outbox = Outbox::new(2, SlowConsumer::DropNewest);
+        assert!(["1", "2", "3"].iter().all(|c| outbox.push(event(c))));
+        assert_eq!(drain(&outbox).await, ["bob: 1", "bob: 2"]);
+
+        let outbox = Outbox::new(2, SlowConsumer::Disconnect);
+        assert!(outbox.push(event("1")) && outbox.push(event("2")));
+        assert!(!outbox.push(event("3")));
+
+        outbox.set_policy(SlowConsumer::DropNewest);
+        assert!(outbox.push(event("3")));
+        assert_eq!(drain(&outbox).await, ["bob: 1", "bob: 2"]);
+    }
+
+    #[tokio::test]
+    async fn closed_outbox_should_stop_writer() {
+        let outbox = Outbox::new(2, SlowConsumer::DropOldest);
+        outbox.push(event("1"));
+        outbox.close();
+        assert!(outbox.pop().await.is_none());
+    }
+}
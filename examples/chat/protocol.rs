@@ -0,0 +1,207 @@
+//! The JSON protocol of WebSocket clients and of TCP clients in json mode.
+//!
+//! Both transports speak it the same way: TCP clients send one request per line and
+//! WebSocket clients one per text frame, e.g. `{"v":1,"type":"join","room":"rust"}`, where `v`
+//! defaults to the current version. Every event comes back as a versioned `Envelope` in a line
+//! or a text frame, like
+//! `{"v":1,"type":"chat","room":"rust","sender":"bob","ts":"...","payload":{"content":"hi"}}`.
+
+use crate::outbox::SlowConsumer;
+use crate::{validate_room, validate_username, Command, Event, Message};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use strum::{Display, EnumString};
+
+pub const VERSION: u32 = 1;
+
+/// How a TCP peer talks, switched with `/proto <mode>` or by logging in with JSON.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Display, EnumString)]
+#[serde(rename_all = "lowercase")]
+#[strum(serialize_all = "lowercase")]
+pub enum Mode {
+    Text,
+    Json,
+}
+
+#[derive(Debug, Deserialize)]
+struct Incoming {
+    #[serde(default = "default_version")]
+    v: u32,
+    #[serde(flatten)]
+    request: Request,
+}
+
+#[derive(Debug, PartialEq, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Request {
+    Login {
+        username: String,
+    },
+    Chat {
+        content: String,
+    },
+    Direct {
+        to: String,
+        content: String,
+    },
+    /// A command line as typed in text mode, e.g. `/join rust`.
+    Command {
+        line: String,
+    },
+    Join {
+        room: String,
+    },
+    Leave,
+    Rooms,
+    Who,
+    Nick {
+        username: String,
+    },
+    History {
+        n: Option<usize>,
+    },
+    Proto {
+        mode: Mode,
+    },
+    Lag,
+    /// Changes what happens to the peer's events once it falls behind.
+    Slow {
+        policy: SlowConsumer,
+    },
+    Quit,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Envelope<'a> {
+    v: u32,
+    #[serde(rename = "type")]
+    kind: String,
+    room: Option<&'a str>,
+    sender: Option<&'a str>,
+    ts: DateTime<Utc>,
+    payload: Value,
+}
+
+impl Request {
+    /// Parses a line or frame, the error is the reply for the client.
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let incoming: Incoming =
+            serde_json::from_str(text).map_err(|e| format!("Invalid request: {}", e))?;
+        if incoming.v != VERSION {
+            return Err(format!(
+                "Unsupported protocol version {}, the server speaks {}",
+                incoming.v, VERSION
+            ));
+        }
+        Ok(incoming.request)
+    }
+
+    /// The command of a request, `None` for the ones the transport handles itself.
+    pub fn command(&self) -> Option<Result<Command<'_>, String>> {
+        let command = match self {
+            Self::Command { line } => Command::parse(line),
+            Self::Direct { to, content } => Ok(Command::Msg { to, content }),
+            Self::Join { room } => validate_room(room).map(|_| Command::Join(room)),
+            Self::Leave => Ok(Command::Leave),
+            Self::Rooms => Ok(Command::Rooms),
+            Self::Who => Ok(Command::Who),
+            Self::Nick { username } => validate_username(username).map(|_| Command::Nick(username)),
+            Self::History { n } => Ok(Command::History(*n)),
+            Self::Lag => Ok(Command::Lag),
+            Self::Slow { policy } => Ok(Command::Slow(*policy)),
+            Self::Quit => Ok(Command::Quit),
+            Self::Login { .. } | Self::Chat { .. } | Self::Proto { .. } => return None,
+        };
+        Some(command)
+    }
+}
+
+impl<'a> Envelope<'a> {
+    pub fn new(event: &'a Event) -> Self {
+        // messages are enums tagged with `type` holding strings and timestamps, so they
+        // always serialize to an object
+        let mut payload = serde_json::to_value(&*event.message).expect("messages serialize");
+        let fields = payload
+            .as_object_mut()
+            .expect("messages serialize to objects");
+        let Some(Value::String(kind)) = fields.remove("type") else {
+            unreachable!("messages are tagged with their type");
+        };
+        let sender = event.message.sender();
+        // the sender is part of the envelope already
+        fields.remove("sender");
+        Self {
+            v: VERSION,
+            kind,
+            room: event.room.as_deref(),
+            sender,
+            ts: event.at,
+            payload,
+        }
+    }
+}
+
+impl Event {
+    /// The event as one line of JSON.
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(&Envelope::new(self)).expect("envelopes serialize")
+    }
+}
+
+impl Message {
+    /// Who the message is from or about, `None` for replies of the server.
+    fn sender(&self) -> Option<&str> {
+        match self {
+            Self::Chat { sender, .. } | Self::Direct { sender, .. } => Some(sender),
+            Self::UserJoined { username, .. } | Self::UserLeft { username, .. } => Some(username),
+            Self::Renamed { new, .. } => Some(new),
+            Self::History { message, .. } => message.sender(),
+            Self::Welcome { .. } | Self::Info { .. } => None,
+        }
+    }
+}
+
+fn default_version() -> u32 {
+    VERSION
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    #[test]
+    fn request_should_parse() {
+        assert_eq!(
+            Request::parse(r#"{"type":"join","room":"rust"}"#),
+            Ok(Request::Join {
+                room: "rust".into()
+            })
+        );
+        assert_eq!(
+            Request::parse(r#"{"v":1,"type":"proto","mode":"text"}"#),
+            Ok(Request::Proto { mode: Mode::Text })
+        );
+        assert_eq!(
+            Request::parse(r#"{"type":"slow","policy":"disconnect"}"#),
+            Ok(Request::Slow {
+                policy: SlowConsumer::Disconnect
+            })
+        );
+        assert!(Request::parse(r#"{"v":2,"type":"quit"}"#).is_err());
+        assert!(Request::parse(r#"{"type":"dance"}"#).is_err());
+        assert!(Request::parse("/quit").is_err());
+    }
+
+    #[test]
+    fn envelope_should_wrap_the_payload() {
+        let event = Event::new(Some("rust"), Arc::new(Message::chat("bob", "hi")));
+        let json: Value = serde_json::from_str(&event.to_json()).unwrap();
+        assert_eq!(json["v"], VERSION);
+        assert_eq!(json["type"], "chat");
+        assert_eq!(json["room"], "rust");
+        assert_eq!(json["sender"], "bob");
+        assert_eq!(json["payload"], serde_json::json!({ "content": "hi" }));
+    }
+}
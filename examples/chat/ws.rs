@@ -0,0 +1,208 @@
+use crate::protocol::Request;
+use crate::{
+    next_input, pump, validate_username, Event, Message, Session, State, IDLE_TIMEOUT,
+    KEEPALIVE_INTERVAL,
+};
+use anyhow::Result;
+use axum::extract::ws::{Message as WsMessage, WebSocket, WebSocketUpgrade};
+use axum::extract::{ConnectInfo, State as AxumState};
+use axum::response::{Html, IntoResponse};
+use axum::routing::get;
+use axum::Router;
+use futures::stream::{SplitSink, SplitStream};
+use futures::{SinkExt, StreamExt};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::timeout;
+use tracing::{info, warn};
+
+/// Peers answer every ping, one that stays silent for a few of them is gone.
+const PONG_TIMEOUT: Duration = KEEPALIVE_INTERVAL.saturating_mul(3);
+
+pub fn router(state: Arc<State>) -> Router {
+    Router::new()
+        .route("/", get(index))
+        .route("/ws", get(upgrade))
+        .with_state(state)
+}
+
+async fn index() -> impl IntoResponse {
+    Html(include_str!("index.html"))
+}
+
+async fn upgrade(
+    ws: WebSocketUpgrade,
+    ConnectInfo(raddr): ConnectInfo<SocketAddr>,
+    AxumState(state): AxumState<Arc<State>>,
+) -> impl IntoResponse {
+    info!("Accepted WebSocket connection from: {}", raddr);
+    ws.on_upgrade(move |socket| async move {
+        if let Err(e) = handle_socket(socket, raddr, state).await {
+            warn!("Failed to handle WebSocket client {}: {}", raddr, e);
+        }
+    })
+}
+
+async fn handle_socket(socket: WebSocket, raddr: SocketAddr, state: Arc<State>) -> Result<()> {
+    let (mut sender, mut receiver) = socket.split();
+
+    let claim = loop {
+        // no pings are sent before the login, so only the idle timeout applies
+        let reply = match next_frame(&mut receiver, IDLE_TIMEOUT).await {
+            None => return Ok(()),
+            Some(Ok(Request::Login { username })) => match validate_username(&username) {
+                Err(e) => e,
+                Ok(()) => match State::claim(&state, &username, raddr) {
+                    Some(claim) => break claim,
+                    None => format!("{} is taken", username),
+                },
+            },
+            Some(Ok(_)) => "Please log in first".into(),
+            Some(Err(e)) => e,
+        };
+        let event = Event::new(None, Arc::new(Message::info(reply)));
+        send_event(&mut sender, &event).await?;
+    };
+
+    let id = claim.id;
+    let (peer, outbox) = state.add(claim);
+    let encode = |event: &Event| WsMessage::Text(event.to_json());
+    let ping = Some(WsMessage::Ping(vec![]));
+    let writer = tokio::spawn(pump(Arc::clone(&outbox), sender, encode, ping));
+    let mut session = Session::new(Arc::clone(&state), id, peer, writer);
+    state.enter(id, &session.peer);
+    let peer = &mut session.peer;
+
+    loop {
+        let read = next_frame(&mut receiver, PONG_TIMEOUT);
+        let Some(frame) = next_input(&outbox, raddr, read).await else {
+            break;
+        };
+        let flow = match frame {
+            Ok(request) => state.request(id, peer, request),
+            Err(e) => {
+                state.send(id, Message::info(e));
+                continue;
+            }
+        };
+        if flow.is_break() {
+            break;
+        }
+    }
+
+    info!("WebSocket client {} disconnected", raddr);
+    Ok(())
+}
+
+/// The next request, `None` once the socket is closed or no frame at all, pongs included,
+/// arrived within `wait`. Frames that don't parse are the reply for the client.
+async fn next_frame(
+    receiver: &mut SplitStream<WebSocket>,
+    wait: Duration,
+) -> Option<Result<Request, String>> {
+    loop {
+        let msg = match timeout(wait, receiver.next()).await {
+            Ok(msg) => msg?,
+            Err(_) => {
+                warn!("No frame in {:?}, dropping the connection", wait);
+                return None;
+            }
+        };
+        match msg {
+            Ok(WsMessage::Text(text)) => return Some(Request::parse(&text)),
+            Ok(WsMessage::Close(_)) => return None,
+            // pings are answered by axum, binary frames aren't part of the protocol
+            Ok(_) => continue,
+            Err(e) => {
+                warn!("Failed to read frame: {}", e);
+                return None;
+            }
+        }
+    }
+}
+
+/// Sends `event` as a versioned envelope, like TCP peers in json mode get it.
+async fn send_event(sender: &mut SplitSink<WebSocket, WsMessage>, event: &Event) -> Result<()> {
+    sender.send(WsMessage::Text(event.to_json())).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::VERSION;
+    use serde_json::{json, Value};
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio_tungstenite::tungstenite::Message as Frame;
+    use tokio_tungstenite::{connect_async, MaybeTlsStream, WebSocketStream};
+
+    type Client = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+    async fn serve(state: Arc<State>) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let app = router(state).into_make_service_with_connect_info::<SocketAddr>();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        addr
+    }
+
+    async fn login(addr: SocketAddr, username: &str) -> Client {
+        let (mut client, _) = connect_async(format!("ws://{}/ws", addr)).await.unwrap();
+        send(
+            &mut client,
+            json!({ "type": "login", "username": username }),
+        )
+        .await;
+        recv(&mut client, "welcome").await;
+        client
+    }
+
+    async fn send(client: &mut Client, request: Value) {
+        client.send(Frame::Text(request.to_string())).await.unwrap();
+    }
+
+    /// The next event of type `kind`, skipping the others.
+    async fn recv(client: &mut Client, kind: &str) -> Value {
+        loop {
+            let frame = timeout(Duration::from_secs(5), client.next())
+                .await
+                .expect("no event in time")
+                .unwrap()
+                .unwrap();
+            if let Frame::Text(text) = frame {
+                let event: Value = serde_json::from_str(&text).unwrap();
+                if event["type"] == kind {
+                    return event;
+                }
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn ws_peers_should_chat_and_be_removed_on_close() {
+        let state = Arc::new(State::default());
+        let addr = serve(Arc::clone(&state)).await;
+
+        let mut alice = login(addr, "alice").await;
+        send(&mut alice, json!({ "type": "join", "room": "rust" })).await;
+        recv(&mut alice, "info").await;
+        let mut bob = login(addr, "bob").await;
+        send(&mut bob, json!({ "type": "join", "room": "rust" })).await;
+        recv(&mut alice, "user_joined").await;
+
+        send(&mut bob, json!({ "type": "chat", "content": "hi" })).await;
+        let event = recv(&mut alice, "chat").await;
+        assert_eq!(event["v"], VERSION);
+        assert_eq!(event["room"], "rust");
+        assert_eq!(event["sender"], "bob");
+        assert_eq!(event["payload"], json!({ "content": "hi" }));
+
+        bob.close(None).await.unwrap();
+        let event = recv(&mut alice, "user_left").await;
+        assert_eq!(event["sender"], "bob");
+        assert_eq!(state.peers.len(), 1);
+        assert!(!state.usernames.contains_key("bob"));
+        assert_eq!(state.members("rust").len(), 1);
+    }
+}
@@ -0,0 +1,76 @@
+use crate::error::AppError;
+use crate::AppState;
+use anyhow::{anyhow, Result};
+use axum::async_trait;
+use axum::extract::FromRequestParts;
+use http::header::AUTHORIZATION;
+use http::request::Parts;
+use http::HeaderMap;
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::sync::Arc;
+
+/// blake3 hash of an API key -> owner, the keys themselves are never kept.
+#[derive(Debug, Default)]
+pub struct ApiKeys(HashMap<blake3::Hash, String>);
+
+/// The owner resolved from the `Authorization: Bearer <key>` or `X-Api-Key` header.
+#[derive(Debug, Clone)]
+pub struct Owner(pub String);
+
+impl ApiKeys {
+    pub fn owner(&self, key: &str) -> Option<&str> {
+        self.0
+            .get(&blake3::hash(key.as_bytes()))
+            .map(|s| s.as_str())
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+}
+
+impl FromStr for ApiKeys {
+    type Err = anyhow::Error;
+
+    /// Parses `owner:hex-hash` pairs separated by commas, e.g. the output of
+    /// `echo -n "$KEY" | b3sum`.
+    fn from_str(s: &str) -> Result<Self> {
+        let mut keys = HashMap::new();
+        for pair in s.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+            let (owner, hash) = pair
+                .split_once(':')
+                .ok_or_else(|| anyhow!("expect owner:hash, got {}", pair))?;
+            let hash = blake3::Hash::from_hex(hash.trim())
+                .map_err(|e| anyhow!("invalid key hash for {}: {}", owner, e))?;
+            keys.insert(hash, owner.trim().to_string());
+        }
+        Ok(Self(keys))
+    }
+}
+
+/// The raw key from `Authorization: Bearer <key>` or `X-Api-Key`.
+pub fn api_key(headers: &HeaderMap) -> Option<&str> {
+    let header = |name| headers.get(name).and_then(|v| v.to_str().ok());
+    header(AUTHORIZATION.as_str())
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .or_else(|| header("x-api-key"))
+        .map(str::trim)
+}
+
+#[async_trait]
+impl FromRequestParts<Arc<AppState>> for Owner {
+    type Rejection = AppError;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &Arc<AppState>,
+    ) -> Result<Self, Self::Rejection> {
+        let key = api_key(&parts.headers).ok_or(AppError::Unauthorized)?;
+        state
+            .api_keys
+            .owner(key)
+            .map(|owner| Owner(owner.to_string()))
+            .ok_or(AppError::Unauthorized)
+    }
+}
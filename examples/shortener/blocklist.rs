@@ -0,0 +1,121 @@
+use anyhow::{Context, Result};
+use std::collections::HashSet;
+use std::path::PathBuf;
+use std::sync::RwLock;
+use std::time::SystemTime;
+
+/// Domains that can't be shortened, read from a file with one domain per line and `#`
+/// comments. A domain also blocks all of its subdomains.
+#[derive(Debug, Default)]
+pub struct Blocklist {
+    path: Option<PathBuf>,
+    domains: RwLock<HashSet<String>>,
+    modified: RwLock<Option<SystemTime>>,
+}
+
+impl Blocklist {
+    /// An empty `path` gives an empty list that never reloads.
+    pub fn load(path: &str) -> Result<Self> {
+        if path.is_empty() {
+            return Ok(Self::default());
+        }
+        let blocklist = Self {
+            path: Some(path.into()),
+            ..Default::default()
+        };
+        blocklist.reload_if_changed()?;
+        Ok(blocklist)
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.path.is_some()
+    }
+
+    pub fn len(&self) -> usize {
+        self.domains.read().unwrap().len()
+    }
+
+    pub fn is_blocked(&self, host: &str) -> bool {
+        let host = host.trim_end_matches('.').to_ascii_lowercase();
+        let domains = self.domains.read().unwrap();
+        let mut suffix = host.as_str();
+        loop {
+            if domains.contains(suffix) {
+                return true;
+            }
+            match suffix.split_once('.') {
+                Some((_, parent)) => suffix = parent,
+                None => return false,
+            }
+        }
+    }
+
+    /// Re-reads the file when its mtime changed, returns whether it did.
+    pub fn reload_if_changed(&self) -> Result<bool> {
+        let Some(path) = &self.path else {
+            return Ok(false);
+        };
+        let modified = std::fs::metadata(path)
+            .and_then(|m| m.modified())
+            .with_context(|| format!("failed to stat blocklist {}", path.display()))?;
+        if *self.modified.read().unwrap() == Some(modified) {
+            return Ok(false);
+        }
+
+        let content = std::fs::read_to_string(path)
+            .with_context(|| format!("failed to read blocklist {}", path.display()))?;
+        *self.domains.write().unwrap() = parse(&content);
+        *self.modified.write().unwrap() = Some(modified);
+        Ok(true)
+    }
+}
+
+fn parse(content: &str) -> HashSet<String> {
+    content
+        .lines()
+        .map(|line| line.split('#').next().unwrap_or_default().trim())
+        .map(|domain| domain.trim_start_matches("*.").trim_matches('.'))
+        .filter(|domain| !domain.is_empty())
+        .map(|domain| domain.to_ascii_lowercase())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::AppConfig;
+    use crate::test_util::*;
+    use http::StatusCode;
+
+    #[test]
+    fn blocklist_should_match_domain_and_subdomains() {
+        let blocklist = Blocklist::default();
+        *blocklist.domains.write().unwrap() =
+            parse("# phishing\nevil.com\n*.Bad.ORG # wildcard\n\n");
+        assert!(blocklist.is_blocked("evil.com"));
+        assert!(blocklist.is_blocked("login.evil.com."));
+        assert!(blocklist.is_blocked("www.bad.org"));
+        assert!(!blocklist.is_blocked("notevil.com"));
+        assert!(!blocklist.is_blocked("com"));
+    }
+
+    #[tokio::test]
+    async fn shorten_blocked_domain_should_403() {
+        let path = std::env::temp_dir().join(format!("blocklist-{}.txt", nanoid::nanoid!()));
+        std::fs::write(&path, "evil.com\n").unwrap();
+        let app = test_app_with(AppConfig {
+            blocklist_file: path.to_string_lossy().into(),
+            ..test_config()
+        })
+        .await;
+        std::fs::remove_file(&path).unwrap();
+
+        let res = post_shorten(
+            &app,
+            serde_json::json!({ "url": "https://login.evil.com/" }),
+        )
+        .await;
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+        shorten_url(&app, "https://www.rust-lang.org/").await;
+    }
+}
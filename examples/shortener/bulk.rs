@@ -0,0 +1,181 @@
+use crate::auth::Owner;
+use crate::error::AppError;
+use crate::{limit, AppState, ShortenReq};
+use axum::body::Bytes;
+use axum::extract::State;
+use axum::response::IntoResponse;
+use axum::Json;
+use http::header::CONTENT_TYPE;
+use http::HeaderMap;
+use serde::Serialize;
+use std::sync::Arc;
+
+/// Large enough for `bulk_limit` items of typical length.
+pub const BODY_LIMIT: usize = 32 * 1024 * 1024;
+
+#[derive(Debug, Serialize)]
+pub struct BulkRes {
+    succeeded: usize,
+    failed: usize,
+    /// One per item, in the order they were sent.
+    results: Vec<BulkItemRes>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(untagged)]
+enum BulkItemRes {
+    Ok { id: String, url: String },
+    Err { error: ItemError },
+}
+
+#[derive(Debug, Serialize)]
+struct ItemError {
+    code: &'static str,
+    message: String,
+}
+
+/// Shortens a JSON array of `ShortenReq`, or one per line with `Content-Type: application/x-ndjson`.
+/// Malformed or rejected items are reported in their slot without failing the others.
+pub async fn bulk(
+    State(state): State<Arc<AppState>>,
+    Owner(owner): Owner,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<impl IntoResponse, AppError> {
+    let reqs = parse_items(&headers, &body)?;
+    if reqs.len() > state.config.bulk_limit {
+        return Err(AppError::InvalidRequest(format!(
+            "at most {} items per request, got {}",
+            state.config.bulk_limit,
+            reqs.len()
+        )));
+    }
+    limit::bulk_items(&state, &owner, reqs.len())?;
+
+    let results: Vec<_> = state
+        .insert_urls(&owner, reqs)
+        .await?
+        .into_iter()
+        .map(|ret| match ret {
+            Ok(record) => BulkItemRes::Ok {
+                url: state.config.short_url(&record.id),
+                id: record.id,
+            },
+            Err(e) => BulkItemRes::Err {
+                error: ItemError {
+                    code: e.code(),
+                    message: e.to_string(),
+                },
+            },
+        })
+        .collect();
+    let succeeded = results
+        .iter()
+        .filter(|r| matches!(r, BulkItemRes::Ok { .. }))
+        .count();
+
+    Ok(Json(BulkRes {
+        succeeded,
+        failed: results.len() - succeeded,
+        results,
+    }))
+}
+
+fn parse_items(
+    headers: &HeaderMap,
+    body: &[u8],
+) -> Result<Vec<Result<ShortenReq, AppError>>, AppError> {
+    let item = |ret: serde_json::Result<ShortenReq>| {
+        ret.map_err(|e| AppError::InvalidRequest(e.to_string()))
+    };
+    let ndjson = headers
+        .get(CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.starts_with("application/x-ndjson"));
+
+    if ndjson {
+        let body = std::str::from_utf8(body)
+            .map_err(|e| AppError::InvalidRequest(format!("body is not utf-8: {}", e)))?;
+        Ok(body
+            .lines()
+            .filter(|line| !line.trim().is_empty())
+            .map(|line| item(serde_json::from_str(line)))
+            .collect())
+    } else {
+        let values: Vec<serde_json::Value> = serde_json::from_slice(body)
+            .map_err(|e| AppError::InvalidRequest(format!("expect a json array: {}", e)))?;
+        Ok(values
+            .into_iter()
+            .map(|value| item(serde_json::from_value(value)))
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::AppConfig;
+    use crate::test_util::*;
+    use axum::body::Body;
+    use http::{Request, StatusCode};
+    use tower::ServiceExt;
+
+    #[tokio::test]
+    async fn bulk_should_report_per_item_results() {
+        let app = test_app().await;
+        let body = serde_json::json!([
+            { "url": "https://www.rust-lang.org/" },
+            { "url": "ftp://example.com/" },
+            { "url": "https://docs.rs/", "alias": "docs" },
+            { "url": "https://crates.io/", "alias": "docs" },
+            { "nope": 1 },
+        ]);
+        let res = send(
+            &app,
+            "POST",
+            "/bulk",
+            ALICE_KEY,
+            Body::from(body.to_string()),
+        )
+        .await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let body = body_json(res).await;
+        assert_eq!(body["succeeded"], 2);
+        assert_eq!(body["failed"], 3);
+        assert_eq!(body["results"][2]["id"], "docs");
+        assert_eq!(body["results"][1]["error"]["code"], "invalid_url");
+        assert_eq!(body["results"][3]["error"]["code"], "alias_taken");
+        assert_eq!(body["results"][4]["error"]["code"], "invalid_request");
+
+        let req = Request::post("/bulk")
+            .header("authorization", format!("Bearer {}", ALICE_KEY))
+            .header("content-type", "application/x-ndjson")
+            .body(Body::from(
+                "{\"url\": \"https://www.rust-lang.org/\"}\n{\"url\": \"https://tokio.rs/\"}\n",
+            ))
+            .unwrap();
+        let res = app.clone().oneshot(req).await.unwrap();
+        assert_eq!(body_json(res).await["succeeded"], 2);
+    }
+
+    #[tokio::test]
+    async fn bulk_should_charge_one_token_per_item() {
+        let app = test_app_with(AppConfig {
+            bulk_limit: 3,
+            bulk_rate_limit: 3,
+            ..test_config()
+        })
+        .await;
+        let bulk = |n: usize| {
+            let app = app.clone();
+            let items = vec![serde_json::json!({ "url": "https://docs.rs/" }); n];
+            let body = Body::from(serde_json::Value::from(items).to_string());
+            async move { send(&app, "POST", "/bulk", ALICE_KEY, body).await.status() }
+        };
+
+        assert_eq!(bulk(2).await, StatusCode::OK);
+        assert_eq!(bulk(2).await, StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(bulk(1).await, StatusCode::OK);
+        // single links come from a different bucket
+        shorten_url(&app, "https://docs.rs/").await;
+    }
+}
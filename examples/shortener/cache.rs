@@ -0,0 +1,171 @@
+use crate::error::AppError;
+use crate::store::{UrlRecord, UrlStore};
+use moka::sync::Cache;
+use moka::Expiry;
+use serde::Serialize;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+/// Read-through cache of `id -> record` in front of the store for the redirect path.
+///
+/// Unknown ids are cached too (for a shorter ttl) so scans of random ids don't reach
+/// the db. Invalidation is local to this process, other instances pick up a retarget
+/// or delete once their entry expires.
+pub struct UrlCache {
+    entries: Option<Cache<String, Cached>>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+#[derive(Debug, Clone)]
+enum Cached {
+    Found(UrlRecord),
+    Missing,
+}
+
+struct CacheExpiry {
+    ttl: Duration,
+    negative_ttl: Duration,
+}
+
+#[derive(Debug, Serialize)]
+pub struct CacheStats {
+    pub entries: u64,
+    pub hits: u64,
+    pub misses: u64,
+}
+
+impl UrlCache {
+    /// A `capacity` of 0 disables caching, every lookup then goes to the store.
+    pub fn new(capacity: u64, ttl: Duration, negative_ttl: Duration) -> Self {
+        let entries = (capacity > 0).then(|| {
+            Cache::builder()
+                .max_capacity(capacity)
+                .expire_after(CacheExpiry { ttl, negative_ttl })
+                .build()
+        });
+        Self {
+            entries,
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    pub async fn get_url(&self, store: &dyn UrlStore, id: &str) -> Result<UrlRecord, AppError> {
+        let Some(entries) = &self.entries else {
+            return store.get_url(id).await;
+        };
+        if let Some(cached) = entries.get(id) {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+            return match cached {
+                Cached::Found(record) => Ok(record),
+                Cached::Missing => Err(AppError::IdNotFound),
+            };
+        }
+
+        self.misses.fetch_add(1, Ordering::Relaxed);
+        match store.get_url(id).await {
+            Ok(record) => {
+                entries.insert(id.to_string(), Cached::Found(record.clone()));
+                Ok(record)
+            }
+            Err(AppError::IdNotFound) => {
+                entries.insert(id.to_string(), Cached::Missing);
+                Err(AppError::IdNotFound)
+            }
+            // never cache a db outage as a missing id
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Drops `id` after it is created, retargeted or deleted.
+    pub fn invalidate(&self, id: &str) {
+        if let Some(entries) = &self.entries {
+            entries.invalidate(id);
+        }
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            entries: self
+                .entries
+                .as_ref()
+                .map(|e| e.entry_count())
+                .unwrap_or_default(),
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl Expiry<String, Cached> for CacheExpiry {
+    fn expire_after_create(&self, _: &String, value: &Cached, _: Instant) -> Option<Duration> {
+        match value {
+            Cached::Found(_) => Some(self.ttl),
+            Cached::Missing => Some(self.negative_ttl),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_util::*;
+    use axum::body::Body;
+    use http::header::LOCATION;
+    use http::StatusCode;
+
+    #[tokio::test]
+    async fn cached_redirect_should_follow_retarget_and_new_alias() {
+        let app = test_app().await;
+
+        // cached as missing, then created
+        assert_eq!(fetch(&app, "/docs").await.status(), StatusCode::NOT_FOUND);
+        let res = post_shorten(
+            &app,
+            serde_json::json!({ "url": "https://docs.rs/", "alias": "docs" }),
+        )
+        .await;
+        assert_eq!(res.status(), StatusCode::CREATED);
+        assert_eq!(
+            fetch(&app, "/docs").await.headers()[LOCATION],
+            "https://docs.rs/"
+        );
+        assert_eq!(
+            fetch(&app, "/docs").await.headers()[LOCATION],
+            "https://docs.rs/"
+        );
+
+        let retarget = serde_json::json!({ "url": "https://crates.io/" }).to_string();
+        let res = send(
+            &app,
+            "PATCH",
+            "/links/docs",
+            ALICE_KEY,
+            Body::from(retarget),
+        )
+        .await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(
+            fetch(&app, "/docs").await.headers()[LOCATION],
+            "https://crates.io/"
+        );
+
+        let res = send(&app, "GET", "/admin/cache", ALICE_KEY, Body::empty()).await;
+        let stats = body_json(res).await;
+        assert_eq!(stats["hits"], 1);
+        assert_eq!(stats["misses"], 3);
+    }
+
+    #[tokio::test]
+    async fn visit_should_refresh_cached_visits() {
+        let app = test_app().await;
+        let body =
+            serde_json::json!({ "url": "https://docs.rs/", "alias": "once", "max_visits": 1 });
+        assert_eq!(post_shorten(&app, body).await.status(), StatusCode::CREATED);
+
+        assert_eq!(fetch(&app, "/once/qr").await.status(), StatusCode::OK);
+        let res = fetch(&app, "/once").await;
+        assert_eq!(res.status(), StatusCode::PERMANENT_REDIRECT);
+        assert_eq!(fetch(&app, "/once/qr").await.status(), StatusCode::GONE);
+    }
+}
@@ -0,0 +1,377 @@
+use crate::error::AppError;
+use crate::links::{ExportFormat, LinkRes, CSV_HEADER};
+use crate::store::UrlRecord;
+use crate::{AppState, ShortenReq};
+use anyhow::{bail, Result};
+use chrono::{DateTime, Utc};
+use clap::{Parser, Subcommand};
+use serde::Deserialize;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+const PAGE_SIZE: i64 = 1000;
+/// Owns the links created from the command line unless `--owner` says otherwise.
+const OPERATOR: &str = "operator";
+
+#[derive(Debug, Parser)]
+#[command(about = "A url shortener")]
+pub struct Cli {
+    /// Apply pending migrations and exit.
+    #[arg(long)]
+    pub migrate_only: bool,
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+/// Everything but `serve` works on the configured store directly, a running server may keep
+/// serving a changed link from its cache until the entry expires.
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Serve the http api, the default.
+    Serve,
+    /// Shorten a url.
+    Create {
+        url: String,
+        #[arg(long)]
+        alias: Option<String>,
+        #[arg(long, default_value = OPERATOR)]
+        owner: String,
+        #[arg(long)]
+        expires_at: Option<DateTime<Utc>>,
+        #[arg(long)]
+        max_visits: Option<i64>,
+        #[arg(long)]
+        interstitial: bool,
+    },
+    /// Show a link without counting a visit, expired and used up ones included.
+    Resolve { id: String },
+    /// Delete a link of any owner.
+    Delete { id: String },
+    /// List the links of an owner, or of every owner.
+    List {
+        #[arg(long)]
+        owner: Option<String>,
+    },
+    /// Create links from a csv with a `url` column and optional `alias`, `expires_at`,
+    /// `max_visits` and `interstitial` columns. `id` works as `alias`, so the csv of
+    /// `export` can be imported as is. Rows already expired or used up are skipped.
+    Import {
+        path: PathBuf,
+        #[arg(long, default_value = OPERATOR)]
+        owner: String,
+    },
+    /// Write all links of an owner, or of every owner, to stdout.
+    Export {
+        #[arg(long)]
+        owner: Option<String>,
+        #[arg(long, value_enum, default_value_t = ExportFormat::Ndjson)]
+        format: ExportFormat,
+    },
+}
+
+#[derive(Debug, Deserialize)]
+struct ImportRow {
+    url: String,
+    #[serde(alias = "id")]
+    alias: Option<String>,
+    expires_at: Option<DateTime<Utc>>,
+    max_visits: Option<i64>,
+    /// Only in exports, to leave out used up links.
+    visits: Option<i64>,
+    #[serde(default)]
+    interstitial: bool,
+}
+
+impl ImportRow {
+    /// Why the row isn't worth importing, such links would only ever be gone.
+    fn skip_reason(&self, now: DateTime<Utc>) -> Option<&'static str> {
+        if self.expires_at.is_some_and(|t| t <= now) {
+            Some("expired")
+        } else if self
+            .max_visits
+            .is_some_and(|n| self.visits.unwrap_or(0) >= n)
+        {
+            Some("used up")
+        } else {
+            None
+        }
+    }
+}
+
+/// Runs one of the admin commands, `serve` is handled by `main`.
+pub async fn run(state: &AppState, command: Command, out: &mut impl Write) -> Result<()> {
+    match command {
+        Command::Serve => bail!("serve is not an admin command"),
+        Command::Create {
+            url,
+            alias,
+            owner,
+            expires_at,
+            max_visits,
+            interstitial,
+        } => {
+            let req = ShortenReq {
+                url,
+                alias,
+                expires_at,
+                max_visits,
+                interstitial,
+            };
+            let new = state.new_url(&owner, req, Utc::now()).await?;
+            let record = state.insert_url(new).await?;
+            writeln!(out, "{}", state.config.short_url(&record.id))?;
+        }
+        Command::Resolve { id } => {
+            // skip the cache and the expiry checks, operators want to see any link as it is
+            let record = state.store.get_url(&id).await?;
+            serde_json::to_writer_pretty(&mut *out, &LinkRes::new(state, record))?;
+            writeln!(out)?;
+        }
+        Command::Delete { id } => {
+            state.store.delete_url(None, &id).await?;
+            state.cache.invalidate(&id);
+            writeln!(out, "Deleted {}", id)?;
+        }
+        Command::List { owner } => {
+            for_each_page(state, owner.as_deref(), |records| {
+                for record in records {
+                    let owner = record.owner.as_deref().unwrap_or("-");
+                    writeln!(out, "{}\t{}\t{}", record.id, owner, record.url)?;
+                }
+                Ok(())
+            })
+            .await?;
+        }
+        Command::Import { path, owner } => import(state, &path, &owner, out).await?,
+        Command::Export { owner, format } => {
+            if let ExportFormat::Csv = format {
+                out.write_all(CSV_HEADER.as_bytes())?;
+            }
+            for_each_page(state, owner.as_deref(), |records| {
+                out.write_all(&format.encode(state, records)?)?;
+                Ok(())
+            })
+            .await?;
+        }
+    }
+    Ok(())
+}
+
+/// Imports in batches of `bulk_limit` through the same path as `POST /bulk`, reporting each
+/// skipped or failed row by its line.
+async fn import(state: &AppState, path: &Path, owner: &str, out: &mut impl Write) -> Result<()> {
+    let mut reader = csv::Reader::from_path(path)?;
+    let headers = reader.headers()?.clone();
+    let rows = reader
+        .records()
+        .map(|row| match row {
+            Ok(row) => (
+                row.position().map(|p| p.line()).unwrap_or_default(),
+                row.deserialize::<ImportRow>(Some(&headers))
+                    .map_err(|e| e.to_string()),
+            ),
+            Err(e) => (
+                e.position().map(|p| p.line()).unwrap_or_default(),
+                Err(e.to_string()),
+            ),
+        })
+        .collect::<Vec<_>>();
+
+    let now = Utc::now();
+    let mut skipped = 0;
+    let mut kept = Vec::with_capacity(rows.len());
+    for (line, row) in rows {
+        match row.as_ref().ok().and_then(|row| row.skip_reason(now)) {
+            Some(reason) => {
+                skipped += 1;
+                writeln!(out, "line {}: skipped, {}", line, reason)?;
+            }
+            None => kept.push((line, row)),
+        }
+    }
+
+    let (mut imported, mut failed) = (0, 0);
+    for batch in kept.chunks(state.config.bulk_limit.max(1)) {
+        let reqs = batch
+            .iter()
+            .map(|(_, row)| match row {
+                Ok(row) => Ok(ShortenReq {
+                    url: row.url.clone(),
+                    alias: row.alias.clone().filter(|alias| !alias.is_empty()),
+                    expires_at: row.expires_at,
+                    max_visits: row.max_visits,
+                    interstitial: row.interstitial,
+                }),
+                Err(e) => Err(AppError::InvalidRequest(e.clone())),
+            })
+            .collect();
+        let results = state.insert_urls(owner, reqs).await?;
+        for ((line, _), ret) in batch.iter().zip(results) {
+            match ret {
+                Ok(_) => imported += 1,
+                Err(e) => {
+                    failed += 1;
+                    writeln!(out, "line {}: {}", line, e)?;
+                }
+            }
+        }
+    }
+
+    writeln!(
+        out,
+        "Imported {} links, {} skipped, {} failed",
+        imported, skipped, failed
+    )?;
+    if failed > 0 {
+        bail!("{} of {} links failed to import", failed, imported + failed);
+    }
+    Ok(())
+}
+
+async fn for_each_page(
+    state: &AppState,
+    owner: Option<&str>,
+    mut f: impl FnMut(Vec<UrlRecord>) -> Result<()>,
+) -> Result<()> {
+    let mut after = None;
+    loop {
+        let records = state
+            .store
+            .list_urls(owner, after.as_deref(), PAGE_SIZE)
+            .await?;
+        let next = (records.len() as i64 == PAGE_SIZE)
+            .then(|| records.last().map(|r| r.id.clone()))
+            .flatten();
+        f(records)?;
+        match next {
+            Some(id) => after = Some(id),
+            None => return Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::tests::new_url;
+    use crate::store::Limits;
+    use crate::test_util::test_state;
+    use clap::CommandFactory;
+
+    async fn run_ok(state: &AppState, args: &[&str]) -> String {
+        let cli = Cli::try_parse_from([&["shortener"], args].concat()).unwrap();
+        let mut out = vec![];
+        run(state, cli.command.unwrap(), &mut out).await.unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn cli_should_default_to_serve() {
+        Cli::command().debug_assert();
+        let cli = Cli::try_parse_from(["shortener", "--migrate-only"]).unwrap();
+        assert!(cli.migrate_only);
+        assert!(cli.command.is_none());
+    }
+
+    #[tokio::test]
+    async fn create_resolve_and_delete_should_work() {
+        let state = test_state().await;
+        let out = run_ok(
+            &state,
+            &[
+                "create",
+                "https://www.rust-lang.org/",
+                "--alias",
+                "rust",
+                "--owner",
+                "alice",
+            ],
+        )
+        .await;
+        assert_eq!(out.trim(), state.config.short_url("rust"));
+
+        let out = run_ok(&state, &["resolve", "rust"]).await;
+        let link: serde_json::Value = serde_json::from_str(&out).unwrap();
+        assert_eq!(link["url"], "https://www.rust-lang.org/");
+
+        run_ok(&state, &["delete", "rust"]).await;
+        assert!(matches!(
+            state.get_url("rust").await,
+            Err(AppError::IdNotFound)
+        ));
+    }
+
+    #[tokio::test]
+    async fn admin_commands_should_see_every_owner() {
+        let state = test_state().await;
+        let out = run_ok(&state, &["create", "https://docs.rs/", "--alias", "docs"]).await;
+        assert_eq!(out.trim(), state.config.short_url("docs"));
+
+        let expired = Limits {
+            expires_at: Some(Utc::now() - chrono::Duration::seconds(1)),
+            max_visits: None,
+        };
+        state
+            .store
+            .insert_url(&new_url("old", expired))
+            .await
+            .unwrap();
+
+        // expired links can still be inspected
+        let out = run_ok(&state, &["resolve", "old"]).await;
+        let link: serde_json::Value = serde_json::from_str(&out).unwrap();
+        assert_eq!(link["owner"], "alice");
+
+        let out = run_ok(&state, &["list"]).await;
+        assert!(out.contains("docs\toperator\thttps://docs.rs/"));
+        assert!(out.contains("old\talice\t"));
+        let out = run_ok(&state, &["list", "--owner", "alice"]).await;
+        assert_eq!(out.lines().count(), 1);
+
+        run_ok(&state, &["delete", "old"]).await;
+        assert!(matches!(
+            state.store.get_url("old").await,
+            Err(AppError::IdNotFound)
+        ));
+    }
+
+    #[tokio::test]
+    async fn import_should_read_exported_csv() {
+        let state = test_state().await;
+        let path =
+            std::env::temp_dir().join(format!("shortener-import-{}.csv", std::process::id()));
+        std::fs::write(
+            &path,
+            "id,url,expires_at,max_visits,visits\n\
+             rust,https://www.rust-lang.org/,,3,1\n\
+             ,https://docs.rs/,,,\n\
+             rust,https://crates.io/,,,\n\
+             old,https://tokio.rs/,2020-01-01T00:00:00Z,,\n\
+             used,https://serde.rs/,,2,2\n",
+        )
+        .unwrap();
+
+        let cli = Cli::try_parse_from([
+            "shortener",
+            "import",
+            path.to_str().unwrap(),
+            "--owner",
+            "alice",
+        ])
+        .unwrap();
+        let mut out = vec![];
+        let ret = run(&state, cli.command.unwrap(), &mut out).await;
+        std::fs::remove_file(&path).unwrap();
+        assert!(ret.is_err());
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.contains("line 4: alias is already taken"));
+        assert!(out.contains("line 5: skipped, expired"));
+        assert!(out.contains("line 6: skipped, used up"));
+        assert!(out.contains("Imported 2 links, 2 skipped, 1 failed"));
+
+        let out = run_ok(&state, &["export", "--owner", "alice", "--format", "csv"]).await;
+        assert!(out.starts_with(CSV_HEADER));
+        assert_eq!(out.lines().count(), 3);
+        assert!(out.contains("rust,http://127.0.0.1:4869/rust,https://www.rust-lang.org/"));
+    }
+}
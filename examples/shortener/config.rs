@@ -0,0 +1,281 @@
+use crate::ids::MAX_ID_LENGTH;
+use anyhow::{bail, Context, Result};
+use http::StatusCode;
+use serde::Deserialize;
+use std::collections::HashSet;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::str::FromStr;
+use std::time::Duration;
+use strum::EnumString;
+use url::Url;
+
+const ENV_PREFIX: &str = "SHORTENER_";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, EnumString)]
+#[serde(rename_all = "lowercase")]
+#[strum(serialize_all = "lowercase")]
+pub enum IdStrategy {
+    /// Random chars, the default.
+    Random,
+    /// A counter in base `id_alphabet`.
+    Sequential,
+    /// Derived from the url, the same url gets the same id on every instance.
+    Hash,
+    /// A counter encoded with a salted alphabet.
+    Sqids,
+}
+
+/// Loaded from the TOML or YAML file named by `SHORTENER_CONFIG`, then every field can be
+/// overridden by its upper-cased `SHORTENER_*` env var, e.g. `SHORTENER_BASE_URL`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AppConfig {
+    /// Public prefix of the returned short links.
+    pub base_url: Url,
+    pub listen_addr: SocketAddr,
+    /// `postgres://...`, `sqlite:...` or `memory://`.
+    pub db_url: String,
+    pub pool_size: u32,
+    pub id_strategy: IdStrategy,
+    /// Min length of generated ids, random and hashed ones grow when they start colliding.
+    pub id_length: usize,
+    /// Chars of generated ids, any of `[A-Za-z0-9_-]`.
+    pub id_alphabet: String,
+    /// Shuffles the alphabet of `sqids` ids, keep it secret to make them harder to guess.
+    pub id_salt: String,
+    pub redirect_status: u16,
+    /// `owner:blake3-hex-of-key` pairs separated by commas.
+    pub api_keys: String,
+    /// Max number of cached ids for redirects, 0 disables the cache.
+    pub cache_capacity: u64,
+    pub cache_ttl_secs: u64,
+    /// How long an unknown id is remembered as missing.
+    pub negative_cache_ttl_secs: u64,
+    /// Max number of items in one `POST /bulk`.
+    pub bulk_limit: usize,
+    /// Links per minute created through `POST /bulk`, per api key, with up to `bulk_limit` at
+    /// once. 0 disables the limit.
+    pub bulk_rate_limit: u32,
+    /// Requests per minute to create links, per api key or client ip. 0 disables the limit.
+    pub shorten_rate_limit: u32,
+    pub shorten_burst: u32,
+    /// Requests per minute to follow links, per client ip. 0 disables the limit.
+    pub redirect_rate_limit: u32,
+    pub redirect_burst: u32,
+    /// Take the client ip from the last `X-Forwarded-For` hop, only safe behind a proxy that
+    /// appends it.
+    pub trust_proxy: bool,
+    /// File of domains that can't be shortened, re-read when it changes.
+    pub blocklist_file: String,
+}
+
+impl Default for AppConfig {
+    fn default() -> Self {
+        Self {
+            base_url: Url::parse("http://127.0.0.1:4869/").unwrap(),
+            listen_addr: "0.0.0.0:4869".parse().unwrap(),
+            db_url: "postgres://localhost/shortener".into(),
+            pool_size: 10,
+            id_strategy: IdStrategy::Random,
+            id_length: 6,
+            id_alphabet: "0123456789abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ".into(),
+            id_salt: String::new(),
+            redirect_status: 308,
+            api_keys: String::new(),
+            cache_capacity: 100_000,
+            cache_ttl_secs: 300,
+            negative_cache_ttl_secs: 30,
+            bulk_limit: 10_000,
+            bulk_rate_limit: 10_000,
+            shorten_rate_limit: 60,
+            shorten_burst: 20,
+            redirect_rate_limit: 600,
+            redirect_burst: 100,
+            trust_proxy: false,
+            blocklist_file: String::new(),
+        }
+    }
+}
+
+impl AppConfig {
+    pub fn load() -> Result<Self> {
+        let mut config = match std::env::var(format!("{}CONFIG", ENV_PREFIX)) {
+            Ok(path) => Self::from_file(&path)?,
+            Err(_) => Self::default(),
+        };
+        config.apply_env()?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let content = std::fs::read_to_string(path)
+            .with_context(|| format!("failed to read config {}", path.display()))?;
+        let config = match path.extension().and_then(|ext| ext.to_str()) {
+            Some("toml") => toml::from_str(&content)?,
+            Some("yaml" | "yml") => serde_yaml::from_str(&content)?,
+            _ => bail!("unsupported config format: {}", path.display()),
+        };
+        Ok(config)
+    }
+
+    pub fn redirect_status(&self) -> StatusCode {
+        StatusCode::from_u16(self.redirect_status).unwrap_or(StatusCode::PERMANENT_REDIRECT)
+    }
+
+    pub fn cache_ttl(&self) -> Duration {
+        Duration::from_secs(self.cache_ttl_secs)
+    }
+
+    pub fn negative_cache_ttl(&self) -> Duration {
+        Duration::from_secs(self.negative_cache_ttl_secs)
+    }
+
+    pub fn short_url(&self, id: &str) -> String {
+        format!("{}{}", self.base_url, id)
+    }
+
+    fn apply_env(&mut self) -> Result<()> {
+        override_from_env("BASE_URL", &mut self.base_url)?;
+        override_from_env("LISTEN_ADDR", &mut self.listen_addr)?;
+        override_from_env("DB_URL", &mut self.db_url)?;
+        override_from_env("POOL_SIZE", &mut self.pool_size)?;
+        override_from_env("ID_STRATEGY", &mut self.id_strategy)?;
+        override_from_env("ID_LENGTH", &mut self.id_length)?;
+        override_from_env("ID_ALPHABET", &mut self.id_alphabet)?;
+        override_from_env("ID_SALT", &mut self.id_salt)?;
+        override_from_env("REDIRECT_STATUS", &mut self.redirect_status)?;
+        override_from_env("API_KEYS", &mut self.api_keys)?;
+        override_from_env("CACHE_CAPACITY", &mut self.cache_capacity)?;
+        override_from_env("CACHE_TTL_SECS", &mut self.cache_ttl_secs)?;
+        override_from_env("NEGATIVE_CACHE_TTL_SECS", &mut self.negative_cache_ttl_secs)?;
+        override_from_env("BULK_LIMIT", &mut self.bulk_limit)?;
+        override_from_env("BULK_RATE_LIMIT", &mut self.bulk_rate_limit)?;
+        override_from_env("SHORTEN_RATE_LIMIT", &mut self.shorten_rate_limit)?;
+        override_from_env("SHORTEN_BURST", &mut self.shorten_burst)?;
+        override_from_env("REDIRECT_RATE_LIMIT", &mut self.redirect_rate_limit)?;
+        override_from_env("REDIRECT_BURST", &mut self.redirect_burst)?;
+        override_from_env("TRUST_PROXY", &mut self.trust_proxy)?;
+        override_from_env("BLOCKLIST_FILE", &mut self.blocklist_file)?;
+        Ok(())
+    }
+
+    fn validate(&mut self) -> Result<()> {
+        if !matches!(self.base_url.scheme(), "http" | "https") || !self.base_url.has_host() {
+            bail!("base_url must be an http(s) url, got {}", self.base_url);
+        }
+        if self.base_url.query().is_some() || self.base_url.fragment().is_some() {
+            bail!("base_url must not have a query or fragment");
+        }
+        if !self.base_url.path().ends_with('/') {
+            let path = format!("{}/", self.base_url.path());
+            self.base_url.set_path(&path);
+        }
+        if self.pool_size == 0 {
+            bail!("pool_size must be at least 1");
+        }
+        if !(4..=MAX_ID_LENGTH).contains(&self.id_length) {
+            bail!(
+                "id_length must be between 4 and {}, got {}",
+                MAX_ID_LENGTH,
+                self.id_length
+            );
+        }
+        let alphabet: HashSet<_> = self.id_alphabet.chars().collect();
+        if alphabet.len() != self.id_alphabet.len() || alphabet.len() < 16 {
+            bail!("id_alphabet must have at least 16 distinct chars");
+        }
+        if !alphabet
+            .iter()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-'))
+        {
+            bail!("id_alphabet may only contain [A-Za-z0-9_-]");
+        }
+        if self.cache_capacity > 0
+            && (self.cache_ttl_secs == 0 || self.negative_cache_ttl_secs == 0)
+        {
+            bail!("cache ttls must be at least 1 second when the cache is enabled");
+        }
+        if self.bulk_limit == 0 {
+            bail!("bulk_limit must be at least 1");
+        }
+        if ![301, 302, 307, 308].contains(&self.redirect_status) {
+            bail!(
+                "redirect_status must be one of 301, 302, 307 or 308, got {}",
+                self.redirect_status
+            );
+        }
+        Ok(())
+    }
+}
+
+fn override_from_env<T>(name: &str, value: &mut T) -> Result<()>
+where
+    T: FromStr,
+    T::Err: std::error::Error + Send + Sync + 'static,
+{
+    let key = format!("{}{}", ENV_PREFIX, name);
+    if let Ok(v) = std::env::var(&key) {
+        *value = v.parse().with_context(|| format!("invalid {}", key))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn shipped_config_should_load_and_validate() {
+        let path = concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/examples/shortener/shortener.toml"
+        );
+        let mut config = AppConfig::from_file(path).unwrap();
+        assert_eq!(config.db_url, "postgres://localhost/shortener");
+        assert_eq!(config.redirect_status(), StatusCode::PERMANENT_REDIRECT);
+        config.validate().unwrap();
+
+        // no other test reads this var
+        std::env::set_var("SHORTENER_POOL_SIZE", "3");
+        config.apply_env().unwrap();
+        std::env::remove_var("SHORTENER_POOL_SIZE");
+        assert_eq!(config.pool_size, 3);
+    }
+
+    #[test]
+    fn yaml_config_should_load() {
+        let path = std::env::temp_dir().join(format!("shortener-{}.yaml", std::process::id()));
+        std::fs::write(
+            &path,
+            "base_url: https://s.example.com/go\nid_strategy: sqids\n",
+        )
+        .unwrap();
+        let config = AppConfig::from_file(&path);
+        std::fs::remove_file(&path).unwrap();
+
+        let mut config = config.unwrap();
+        assert_eq!(config.id_strategy, IdStrategy::Sqids);
+        config.validate().unwrap();
+        assert_eq!(config.short_url("abc"), "https://s.example.com/go/abc");
+    }
+
+    #[test]
+    fn validate_should_reject_bad_values() {
+        let mut config = AppConfig {
+            redirect_status: 200,
+            ..Default::default()
+        };
+        assert!(config.validate().is_err());
+
+        for base_url in ["ftp://example.com/", "https://example.com/?a=1"] {
+            let mut config = AppConfig {
+                base_url: Url::parse(base_url).unwrap(),
+                ..Default::default()
+            };
+            assert!(config.validate().is_err(), "{}", base_url);
+        }
+    }
+}
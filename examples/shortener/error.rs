@@ -0,0 +1,178 @@
+use axum::extract::rejection::{JsonRejection, QueryRejection};
+use axum::extract::{FromRequest, FromRequestParts, Request};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use http::header::RETRY_AFTER;
+use http::{HeaderValue, StatusCode};
+use serde::Serialize;
+use std::time::Duration;
+use thiserror::Error;
+use tracing::warn;
+
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+
+tokio::task_local! {
+    static REQUEST_ID: String;
+}
+
+#[derive(Error, Debug, Clone)]
+pub enum AppError {
+    #[error("invalid request: {0}")]
+    InvalidRequest(String),
+    #[error("invalid url: {0}")]
+    InvalidUrl(String),
+    #[error("invalid alias: {0}")]
+    InvalidAlias(String),
+    #[error("invalid limit: {0}")]
+    InvalidLimit(String),
+    #[error("missing or invalid api key")]
+    Unauthorized,
+    #[error("links to {0} are not allowed")]
+    BlockedDomain(String),
+    #[error("url not found")]
+    IdNotFound,
+    #[error("alias is already taken")]
+    AliasTaken,
+    /// The generated id is taken, callers retry with a new one.
+    #[error("id already exists")]
+    IdExists,
+    #[error("url has expired or reached its visit limit")]
+    Gone,
+    #[error("too many requests, retry in {}s", retry_after_secs(.0))]
+    RateLimited(Duration),
+    #[error("internal error")]
+    Internal,
+    #[error("database unavailable")]
+    DbUnavailable,
+    #[error("no free id found, please retry")]
+    IdSpaceExhausted,
+}
+
+/// The body of every error response.
+#[derive(Debug, Serialize)]
+pub struct ErrorBody {
+    /// Stable and machine-readable, safe to match on.
+    pub code: &'static str,
+    pub message: String,
+    pub request_id: Option<String>,
+}
+
+/// `Json` that reports malformed bodies as `AppError`.
+#[derive(FromRequest)]
+#[from_request(via(axum::Json), rejection(AppError))]
+pub struct AppJson<T>(pub T);
+
+/// `Query` that reports malformed query strings as `AppError`.
+#[derive(FromRequestParts)]
+#[from_request(via(axum::extract::Query), rejection(AppError))]
+pub struct AppQuery<T>(pub T);
+
+impl AppError {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            Self::InvalidRequest(_)
+            | Self::InvalidUrl(_)
+            | Self::InvalidAlias(_)
+            | Self::InvalidLimit(_) => StatusCode::BAD_REQUEST,
+            Self::Unauthorized => StatusCode::UNAUTHORIZED,
+            Self::BlockedDomain(_) => StatusCode::FORBIDDEN,
+            Self::IdNotFound => StatusCode::NOT_FOUND,
+            Self::AliasTaken | Self::IdExists => StatusCode::CONFLICT,
+            Self::Gone => StatusCode::GONE,
+            Self::RateLimited(_) => StatusCode::TOO_MANY_REQUESTS,
+            Self::Internal => StatusCode::INTERNAL_SERVER_ERROR,
+            Self::DbUnavailable | Self::IdSpaceExhausted => StatusCode::SERVICE_UNAVAILABLE,
+        }
+    }
+
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::InvalidRequest(_) => "invalid_request",
+            Self::InvalidUrl(_) => "invalid_url",
+            Self::InvalidAlias(_) => "invalid_alias",
+            Self::InvalidLimit(_) => "invalid_limit",
+            Self::Unauthorized => "unauthorized",
+            Self::BlockedDomain(_) => "blocked_domain",
+            Self::IdNotFound => "not_found",
+            Self::AliasTaken => "alias_taken",
+            Self::IdExists => "conflict",
+            Self::Gone => "gone",
+            Self::RateLimited(_) => "rate_limited",
+            Self::Internal => "internal",
+            Self::DbUnavailable => "db_unavailable",
+            Self::IdSpaceExhausted => "id_space_exhausted",
+        }
+    }
+}
+
+impl From<sqlx::Error> for AppError {
+    fn from(e: sqlx::Error) -> Self {
+        match e {
+            sqlx::Error::RowNotFound => Self::IdNotFound,
+            sqlx::Error::Database(e) if e.is_unique_violation() => Self::IdExists,
+            sqlx::Error::Io(_)
+            | sqlx::Error::Tls(_)
+            | sqlx::Error::PoolTimedOut
+            | sqlx::Error::PoolClosed
+            | sqlx::Error::WorkerCrashed => {
+                warn!("db unavailable: {:?}", e);
+                Self::DbUnavailable
+            }
+            _ => {
+                warn!("db error: {:?}", e);
+                Self::Internal
+            }
+        }
+    }
+}
+
+impl From<JsonRejection> for AppError {
+    fn from(e: JsonRejection) -> Self {
+        Self::InvalidRequest(e.body_text())
+    }
+}
+
+impl From<QueryRejection> for AppError {
+    fn from(e: QueryRejection) -> Self {
+        Self::InvalidRequest(e.body_text())
+    }
+}
+
+impl IntoResponse for AppError {
+    fn into_response(self) -> Response {
+        let body = ErrorBody {
+            code: self.code(),
+            message: self.to_string(),
+            request_id: REQUEST_ID.try_with(|id| id.clone()).ok(),
+        };
+        let mut res = (self.status(), Json(body)).into_response();
+        if let Self::RateLimited(retry) = self {
+            res.headers_mut()
+                .insert(RETRY_AFTER, HeaderValue::from(retry_after_secs(&retry)));
+        }
+        res
+    }
+}
+
+/// Whole seconds for `Retry-After`, rounded up so clients never retry too early.
+fn retry_after_secs(retry: &Duration) -> u64 {
+    retry.as_secs_f64().ceil().max(1.0) as u64
+}
+
+/// Tags every request with the caller's `X-Request-Id` or a fresh one, and echoes it back.
+pub async fn request_id(req: Request, next: Next) -> Response {
+    let id = req
+        .headers()
+        .get(REQUEST_ID_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .filter(|v| !v.is_empty() && v.len() <= 64)
+        .map(|v| v.to_string())
+        .unwrap_or_else(|| nanoid::nanoid!(16));
+
+    let mut res = REQUEST_ID.scope(id.clone(), next.run(req)).await;
+    if let Ok(v) = HeaderValue::from_str(&id) {
+        res.headers_mut().insert(REQUEST_ID_HEADER, v);
+    }
+    res
+}
@@ -0,0 +1,274 @@
+use crate::config::{AppConfig, IdStrategy};
+use crate::error::AppError;
+use crate::store::UrlStore;
+use async_trait::async_trait;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use tracing::info;
+
+pub const MAX_ID_LENGTH: usize = 32;
+/// Random and hashed ids get one char longer after this many collisions on a single url.
+const GROW_AFTER: usize = 3;
+
+#[async_trait]
+pub trait IdGenerator: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    /// A candidate id for `url`, `attempt` counts the collisions so far for it. Integer
+    /// based strategies draw their key from `store.next_seq()`.
+    async fn generate(
+        &self,
+        store: &dyn UrlStore,
+        url: &str,
+        attempt: usize,
+    ) -> Result<String, AppError>;
+}
+
+/// nanoid style, random chars of the alphabet. The length grows for good once a url
+/// needs `GROW_AFTER` attempts, as that means the keyspace is filling up.
+pub struct RandomIds {
+    alphabet: Vec<char>,
+    length: AtomicUsize,
+}
+
+/// Base-N of a db sequence, left padded to the configured length. Short, but guessable.
+pub struct SequentialIds {
+    alphabet: Vec<char>,
+    length: usize,
+}
+
+/// Derived from the blake3 hash of the url, so the same url always maps to the same id.
+pub struct HashIds {
+    alphabet: Vec<char>,
+    length: usize,
+}
+
+/// Sqids style encoding of a db sequence: unique like `SequentialIds` but shuffled by a
+/// salt, so consecutive ids don't look consecutive.
+pub struct SqidsIds {
+    alphabet: Vec<char>,
+    length: usize,
+}
+
+pub fn from_config(config: &AppConfig) -> Box<dyn IdGenerator> {
+    let alphabet: Vec<char> = config.id_alphabet.chars().collect();
+    let length = config.id_length;
+    match config.id_strategy {
+        IdStrategy::Random => Box::new(RandomIds {
+            alphabet,
+            length: AtomicUsize::new(length),
+        }),
+        IdStrategy::Sequential => Box::new(SequentialIds { alphabet, length }),
+        IdStrategy::Hash => Box::new(HashIds { alphabet, length }),
+        IdStrategy::Sqids => Box::new(SqidsIds {
+            alphabet: shuffle(alphabet, config.id_salt.as_bytes()),
+            length,
+        }),
+    }
+}
+
+#[async_trait]
+impl IdGenerator for RandomIds {
+    fn name(&self) -> &'static str {
+        "random"
+    }
+
+    async fn generate(
+        &self,
+        _: &dyn UrlStore,
+        _: &str,
+        attempt: usize,
+    ) -> Result<String, AppError> {
+        let mut length = self.length.load(Ordering::Relaxed);
+        if attempt > 0 && attempt.is_multiple_of(GROW_AFTER) && length < MAX_ID_LENGTH {
+            // racing inserts may both see the old length, only one of them grows it
+            if self
+                .length
+                .compare_exchange(length, length + 1, Ordering::Relaxed, Ordering::Relaxed)
+                .is_ok()
+            {
+                info!(
+                    "Random ids are colliding, growing them to {} chars",
+                    length + 1
+                );
+            }
+            length = self.length.load(Ordering::Relaxed);
+        }
+        Ok(nanoid::format(
+            nanoid::rngs::default,
+            &self.alphabet,
+            length,
+        ))
+    }
+}
+
+#[async_trait]
+impl IdGenerator for SequentialIds {
+    fn name(&self) -> &'static str {
+        "sequential"
+    }
+
+    async fn generate(&self, store: &dyn UrlStore, _: &str, _: usize) -> Result<String, AppError> {
+        let n = store.next_seq().await?;
+        let digits = to_base(n as u128, &self.alphabet);
+        let padding = self.length.saturating_sub(digits.chars().count());
+        Ok(std::iter::repeat_n(self.alphabet[0], padding)
+            .chain(digits.chars())
+            .collect())
+    }
+}
+
+#[async_trait]
+impl IdGenerator for HashIds {
+    fn name(&self) -> &'static str {
+        "hash"
+    }
+
+    async fn generate(
+        &self,
+        _: &dyn UrlStore,
+        url: &str,
+        attempt: usize,
+    ) -> Result<String, AppError> {
+        // attempt 0 hashes the bare url so the id stays stable across instances
+        let mut hasher = blake3::Hasher::new();
+        hasher.update(url.as_bytes());
+        if attempt > 0 {
+            hasher.update(&(attempt as u64).to_le_bytes());
+        }
+        let length = (self.length + attempt / GROW_AFTER).min(MAX_ID_LENGTH);
+
+        let mut id = String::with_capacity(length);
+        let mut reader = hasher.finalize_xof();
+        while id.len() < length {
+            let mut buf = [0; 16];
+            reader.fill(&mut buf);
+            id.push_str(&to_base(u128::from_le_bytes(buf), &self.alphabet));
+        }
+        id.truncate(length);
+        Ok(id)
+    }
+}
+
+#[async_trait]
+impl IdGenerator for SqidsIds {
+    fn name(&self) -> &'static str {
+        "sqids"
+    }
+
+    async fn generate(&self, store: &dyn UrlStore, _: &str, _: usize) -> Result<String, AppError> {
+        let n = store.next_seq().await?;
+        Ok(self.encode(n as u64))
+    }
+}
+
+impl SqidsIds {
+    /// The first char picks a rotation of the alphabet, its first char separates the number
+    /// from the padding and the rest encode the number. Distinct numbers never share an id.
+    fn encode(&self, n: u64) -> String {
+        let len = self.alphabet.len();
+        let offset = (n as usize % len + self.alphabet[n as usize % len] as usize) % len;
+        let mut alphabet = self.alphabet.clone();
+        alphabet.rotate_left(offset);
+        let prefix = alphabet[0];
+        alphabet.reverse();
+        let (separator, digits) = alphabet.split_first().expect("alphabet is not empty");
+
+        let mut id = String::with_capacity(self.length);
+        id.push(prefix);
+        id.push_str(&to_base(n as u128, digits));
+        if id.len() < self.length {
+            id.push(*separator);
+            let mut filler = shuffle(digits.to_vec(), &n.to_le_bytes())
+                .into_iter()
+                .cycle();
+            while id.len() < self.length {
+                id.extend(filler.next());
+            }
+        }
+        id
+    }
+}
+
+/// `n` in base `alphabet.len()`, most significant digit first.
+fn to_base(mut n: u128, alphabet: &[char]) -> String {
+    let base = alphabet.len() as u128;
+    let mut digits = vec![];
+    loop {
+        digits.push(alphabet[(n % base) as usize]);
+        n /= base;
+        if n == 0 {
+            break;
+        }
+    }
+    digits.iter().rev().collect()
+}
+
+/// Deterministic Fisher-Yates shuffle seeded by `salt`.
+fn shuffle(mut alphabet: Vec<char>, salt: &[u8]) -> Vec<char> {
+    let mut reader = blake3::Hasher::new().update(salt).finalize_xof();
+    for i in (1..alphabet.len()).rev() {
+        let mut buf = [0; 8];
+        reader.fill(&mut buf);
+        let j = (u64::from_le_bytes(buf) % (i as u64 + 1)) as usize;
+        alphabet.swap(i, j);
+    }
+    alphabet
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::MemoryStore;
+    use std::collections::HashSet;
+
+    fn base62() -> Vec<char> {
+        ('0'..='9').chain('a'..='z').chain('A'..='Z').collect()
+    }
+
+    #[test]
+    fn sqids_should_be_unique_and_padded() {
+        let sqids = SqidsIds {
+            alphabet: shuffle(base62(), b"salt"),
+            length: 6,
+        };
+        let ids: HashSet<_> = (0..20_000).map(|n| sqids.encode(n)).collect();
+        assert_eq!(ids.len(), 20_000);
+        assert!(ids.iter().all(|id| id.len() >= 6));
+    }
+
+    #[tokio::test]
+    async fn sequential_ids_should_be_padded() {
+        let store = MemoryStore::default();
+        let ids = SequentialIds {
+            alphabet: base62(),
+            length: 6,
+        };
+        assert_eq!(ids.generate(&store, "", 0).await.unwrap(), "000001");
+        assert_eq!(ids.generate(&store, "", 0).await.unwrap(), "000002");
+    }
+
+    #[tokio::test]
+    async fn hash_ids_should_be_stable_and_grow() {
+        let store = MemoryStore::default();
+        let ids = HashIds {
+            alphabet: base62(),
+            length: 6,
+        };
+        let url = "https://www.rust-lang.org/";
+        let id = ids.generate(&store, url, 0).await.unwrap();
+        assert_eq!(id, ids.generate(&store, url, 0).await.unwrap());
+        assert_eq!(id.len(), 6);
+        assert_ne!(id, ids.generate(&store, url, 1).await.unwrap());
+        assert_eq!(
+            ids.generate(&store, url, GROW_AFTER).await.unwrap().len(),
+            7
+        );
+    }
+
+    #[test]
+    fn to_base_should_work() {
+        assert_eq!(to_base(0, &base62()), "0");
+        assert_eq!(to_base(61, &base62()), "Z");
+        assert_eq!(to_base(62, &base62()), "10");
+    }
+}
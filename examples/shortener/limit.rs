@@ -0,0 +1,205 @@
+use crate::error::AppError;
+use crate::{auth, client_ip, AppState};
+use axum::extract::{ConnectInfo, Request, State};
+use axum::middleware::Next;
+use axum::response::Response;
+use dashmap::DashMap;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Token buckets keyed by client, each refilled at `per_minute` and holding up to `burst`.
+#[derive(Debug)]
+pub struct RateLimiter {
+    rate: f64,
+    burst: f64,
+    buckets: DashMap<String, Bucket>,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl RateLimiter {
+    /// A `per_minute` of 0 disables the limit.
+    pub fn new(per_minute: u32, burst: u32) -> Self {
+        Self {
+            rate: per_minute as f64 / 60.0,
+            burst: burst.max(1) as f64,
+            buckets: DashMap::new(),
+        }
+    }
+
+    /// Takes a token from `key`'s bucket, or returns how long until the next one.
+    pub fn check(&self, key: &str) -> Result<(), Duration> {
+        self.check_n(key, 1)
+    }
+
+    /// Takes `n` tokens at once or none, returns how long until there are enough. More than
+    /// `burst` never fit.
+    pub fn check_n(&self, key: &str, n: u32) -> Result<(), Duration> {
+        if self.rate == 0.0 {
+            return Ok(());
+        }
+        let now = Instant::now();
+        let mut bucket = self.buckets.entry(key.to_string()).or_insert(Bucket {
+            tokens: self.burst,
+            updated: now,
+        });
+        bucket.tokens = self.refill(&bucket, now);
+        bucket.updated = now;
+
+        let n = n as f64;
+        if bucket.tokens >= n {
+            bucket.tokens -= n;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((n - bucket.tokens) / self.rate))
+        }
+    }
+
+    /// Drops the buckets that are full again, they behave the same as missing ones.
+    pub fn purge(&self) {
+        let now = Instant::now();
+        self.buckets
+            .retain(|_, bucket| self.refill(bucket, now) < self.burst);
+    }
+
+    fn refill(&self, bucket: &Bucket, now: Instant) -> f64 {
+        let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+        (bucket.tokens + elapsed * self.rate).min(self.burst)
+    }
+}
+
+/// Limits creating links per API key, or per client ip for requests without a valid one.
+pub async fn shorten(
+    State(state): State<Arc<AppState>>,
+    req: Request,
+    next: Next,
+) -> Result<Response, AppError> {
+    let key = match auth::api_key(req.headers()).and_then(|key| state.api_keys.owner(key)) {
+        Some(owner) => format!("owner:{}", owner),
+        None => format!("ip:{}", peer_ip(&state, &req)),
+    };
+    state
+        .shorten_limit
+        .check(&key)
+        .map_err(AppError::RateLimited)?;
+    Ok(next.run(req).await)
+}
+
+/// Limits following links per client ip.
+pub async fn redirect(
+    State(state): State<Arc<AppState>>,
+    req: Request,
+    next: Next,
+) -> Result<Response, AppError> {
+    let key = peer_ip(&state, &req);
+    state
+        .redirect_limit
+        .check(&key)
+        .map_err(AppError::RateLimited)?;
+    Ok(next.run(req).await)
+}
+
+/// Charges the `items` of a `POST /bulk` to `owner`, one token each.
+pub fn bulk_items(state: &AppState, owner: &str, items: usize) -> Result<(), AppError> {
+    let items = u32::try_from(items).unwrap_or(u32::MAX);
+    state
+        .bulk_item_limit
+        .check_n(&format!("owner:{}", owner), items)
+        .map_err(AppError::RateLimited)
+}
+
+fn peer_ip(state: &AppState, req: &Request) -> String {
+    let peer = req
+        .extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|ConnectInfo(addr)| *addr);
+    client_ip(req.headers(), peer, state.config.trust_proxy).unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::AppConfig;
+    use crate::test_util::*;
+    use axum::body::Body;
+    use http::StatusCode;
+    use tower::ServiceExt;
+
+    #[test]
+    fn rate_limiter_should_allow_burst_then_throttle() {
+        let limiter = RateLimiter::new(60, 2);
+        assert!(limiter.check("a").is_ok());
+        assert!(limiter.check("a").is_ok());
+        let retry = limiter.check("a").unwrap_err();
+        assert!(retry <= Duration::from_secs(1));
+        // other clients have their own bucket
+        assert!(limiter.check("b").is_ok());
+        // a batch takes all of its tokens or none
+        assert!(limiter.check_n("b", 2).is_err());
+        assert!(limiter.check("b").is_ok());
+
+        limiter.purge();
+        assert_eq!(limiter.buckets.len(), 2);
+    }
+
+    #[test]
+    fn zero_rate_should_disable_limit() {
+        let limiter = RateLimiter::new(0, 1);
+        for _ in 0..10 {
+            assert!(limiter.check("a").is_ok());
+        }
+    }
+
+    #[tokio::test]
+    async fn over_limit_should_429_with_retry_after() {
+        let app = test_app_with(AppConfig {
+            shorten_burst: 2,
+            ..test_config()
+        })
+        .await;
+        for _ in 0..2 {
+            shorten_url(&app, "https://www.rust-lang.org/").await;
+        }
+        let res = post_shorten(&app, serde_json::json!({ "url": "https://docs.rs/" })).await;
+        assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(res.headers()["retry-after"], "1");
+
+        // bob has a separate bucket
+        let body = serde_json::json!({ "url": "https://docs.rs/" }).to_string();
+        let res = send(&app, "POST", "/", BOB_KEY, Body::from(body)).await;
+        assert_eq!(res.status(), StatusCode::CREATED);
+    }
+
+    #[tokio::test]
+    async fn spoofed_forwarded_for_should_not_reset_bucket() {
+        let app = test_app_with(AppConfig {
+            redirect_burst: 1,
+            trust_proxy: true,
+            ..test_config()
+        })
+        .await;
+        let redirect = |forwarded_for: &'static str| {
+            let app = app.clone();
+            async move {
+                let req = http::Request::get("/nope42")
+                    .header("x-forwarded-for", forwarded_for)
+                    .body(Body::empty())
+                    .unwrap();
+                app.oneshot(req).await.unwrap().status()
+            }
+        };
+
+        assert_eq!(redirect("10.0.0.1, 192.0.2.7").await, StatusCode::NOT_FOUND);
+        // the proxy appended the same client, whatever it claims to come from
+        assert_eq!(
+            redirect("10.0.0.2, 192.0.2.7").await,
+            StatusCode::TOO_MANY_REQUESTS
+        );
+        assert_eq!(redirect("192.0.2.8").await, StatusCode::NOT_FOUND);
+    }
+}
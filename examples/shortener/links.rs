@@ -0,0 +1,274 @@
+use crate::auth::Owner;
+use crate::error::{AppError, AppJson, AppQuery};
+use crate::store::UrlRecord;
+use crate::AppState;
+use axum::body::{Body, Bytes};
+use axum::extract::{Path, State};
+use axum::response::IntoResponse;
+use axum::Json;
+use chrono::{DateTime, Utc};
+use clap::ValueEnum;
+use futures::{stream, StreamExt, TryStreamExt};
+use http::header::{CONTENT_DISPOSITION, CONTENT_TYPE};
+use http::StatusCode;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use tracing::warn;
+
+const DEFAULT_PAGE_SIZE: i64 = 50;
+const MAX_PAGE_SIZE: i64 = 500;
+const EXPORT_PAGE_SIZE: i64 = 1000;
+pub const CSV_HEADER: &str =
+    "id,short_url,url,created_at,expires_at,max_visits,visits,interstitial\n";
+
+#[derive(Debug, Deserialize)]
+pub struct ListReq {
+    limit: Option<i64>,
+    /// The `next` cursor of the previous page.
+    after: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ListRes {
+    links: Vec<LinkRes>,
+    next: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct LinkRes {
+    short_url: String,
+    #[serde(flatten)]
+    record: UrlRecord,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct UpdateReq {
+    url: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ExportReq {
+    #[serde(default)]
+    format: ExportFormat,
+}
+
+#[derive(Debug, Default, Clone, Copy, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum ExportFormat {
+    #[default]
+    Ndjson,
+    Csv,
+}
+
+#[derive(Debug, Serialize)]
+struct CsvRow<'a> {
+    id: &'a str,
+    short_url: String,
+    url: &'a str,
+    created_at: DateTime<Utc>,
+    expires_at: Option<DateTime<Utc>>,
+    max_visits: Option<i64>,
+    visits: i64,
+    interstitial: bool,
+}
+
+impl LinkRes {
+    pub fn new(state: &AppState, record: UrlRecord) -> Self {
+        Self {
+            short_url: state.config.short_url(&record.id),
+            record,
+        }
+    }
+}
+
+pub async fn list(
+    State(state): State<Arc<AppState>>,
+    Owner(owner): Owner,
+    AppQuery(query): AppQuery<ListReq>,
+) -> Result<impl IntoResponse, AppError> {
+    let limit = query
+        .limit
+        .unwrap_or(DEFAULT_PAGE_SIZE)
+        .clamp(1, MAX_PAGE_SIZE);
+    let records = state
+        .store
+        .list_urls(Some(&owner), query.after.as_deref(), limit)
+        .await?;
+
+    let next = (records.len() as i64 == limit)
+        .then(|| records.last().map(|r| r.id.clone()))
+        .flatten();
+    let links = records
+        .into_iter()
+        .map(|record| LinkRes::new(&state, record))
+        .collect();
+
+    Ok(Json(ListRes { links, next }))
+}
+
+pub async fn update(
+    State(state): State<Arc<AppState>>,
+    Owner(owner): Owner,
+    Path(id): Path<String>,
+    AppJson(data): AppJson<UpdateReq>,
+) -> Result<impl IntoResponse, AppError> {
+    let url = state.check_url(&data.url)?;
+    let record = state.store.update_url(&owner, &id, &url).await?;
+    state.cache.invalidate(&id);
+    Ok(Json(LinkRes::new(&state, record)))
+}
+
+pub async fn delete(
+    State(state): State<Arc<AppState>>,
+    Owner(owner): Owner,
+    Path(id): Path<String>,
+) -> Result<impl IntoResponse, AppError> {
+    state.store.delete_url(Some(&owner), &id).await?;
+    state.cache.invalidate(&id);
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Streams every link of the owner page by page, so exports of any size use constant memory.
+pub async fn export(
+    State(state): State<Arc<AppState>>,
+    Owner(owner): Owner,
+    AppQuery(query): AppQuery<ExportReq>,
+) -> impl IntoResponse {
+    let format = query.format;
+    // `None` once the last page was sent, otherwise the cursor of the next one
+    let pages = stream::try_unfold(Some(None::<String>), move |after| {
+        let state = Arc::clone(&state);
+        let owner = owner.clone();
+        async move {
+            let Some(after) = after else {
+                return Ok(None);
+            };
+            let records = state
+                .store
+                .list_urls(Some(&owner), after.as_deref(), EXPORT_PAGE_SIZE)
+                .await?;
+            let next = (records.len() as i64 == EXPORT_PAGE_SIZE)
+                .then(|| records.last().map(|r| r.id.clone()))
+                .flatten();
+            let chunk = format.encode(&state, records)?;
+            Ok::<_, AppError>(Some((chunk, next.map(Some))))
+        }
+    })
+    .inspect_err(|e| warn!("Failed to export links: {}", e));
+
+    let header = match format {
+        ExportFormat::Csv => Bytes::from_static(CSV_HEADER.as_bytes()),
+        ExportFormat::Ndjson => Bytes::new(),
+    };
+    let body = Body::from_stream(stream::once(async { Ok(header) }).chain(pages));
+
+    let headers = [
+        (CONTENT_TYPE, format.content_type()),
+        (CONTENT_DISPOSITION, format.content_disposition()),
+    ];
+    (headers, body)
+}
+
+impl ExportFormat {
+    fn content_type(&self) -> &'static str {
+        match self {
+            Self::Ndjson => "application/x-ndjson",
+            Self::Csv => "text/csv",
+        }
+    }
+
+    fn content_disposition(&self) -> &'static str {
+        match self {
+            Self::Ndjson => r#"attachment; filename="links.ndjson""#,
+            Self::Csv => r#"attachment; filename="links.csv""#,
+        }
+    }
+
+    pub fn encode(&self, state: &AppState, records: Vec<UrlRecord>) -> Result<Bytes, AppError> {
+        let mut buf = Vec::new();
+        match self {
+            Self::Ndjson => {
+                for record in records {
+                    serde_json::to_writer(&mut buf, &LinkRes::new(state, record))
+                        .map_err(|_| AppError::Internal)?;
+                    buf.push(b'\n');
+                }
+            }
+            Self::Csv => {
+                let mut writer = csv::WriterBuilder::new()
+                    .has_headers(false)
+                    .from_writer(&mut buf);
+                for record in &records {
+                    let row = CsvRow {
+                        id: &record.id,
+                        short_url: state.config.short_url(&record.id),
+                        url: &record.url,
+                        created_at: record.created_at,
+                        expires_at: record.expires_at,
+                        max_visits: record.max_visits,
+                        visits: record.visits,
+                        interstitial: record.interstitial,
+                    };
+                    writer.serialize(row).map_err(|_| AppError::Internal)?;
+                }
+                writer.flush().map_err(|_| AppError::Internal)?;
+            }
+        }
+        Ok(buf.into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_util::*;
+    use axum::body::Body;
+    use http::StatusCode;
+
+    #[tokio::test]
+    async fn links_should_be_scoped_to_owner() {
+        let app = test_app().await;
+        let id = shorten_url(&app, "https://www.rust-lang.org/").await;
+
+        let res = send(&app, "GET", "/links", ALICE_KEY, Body::empty()).await;
+        let list = body_json(res).await;
+        assert_eq!(list["links"][0]["id"], id.as_str());
+
+        let res = send(&app, "GET", "/links", BOB_KEY, Body::empty()).await;
+        let list = body_json(res).await;
+        assert_eq!(list["links"].as_array().unwrap().len(), 0);
+
+        let uri = format!("/links/{}", id);
+        let retarget = serde_json::json!({ "url": "https://docs.rs/" }).to_string();
+        let res = send(&app, "PATCH", &uri, BOB_KEY, Body::from(retarget.clone())).await;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+        let res = send(&app, "PATCH", &uri, ALICE_KEY, Body::from(retarget)).await;
+        assert_eq!(res.status(), StatusCode::OK);
+
+        let res = send(&app, "DELETE", &uri, BOB_KEY, Body::empty()).await;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+        let res = send(&app, "DELETE", &uri, ALICE_KEY, Body::empty()).await;
+        assert_eq!(res.status(), StatusCode::NO_CONTENT);
+
+        assert_eq!(
+            fetch(&app, &format!("/{}", id)).await.status(),
+            StatusCode::NOT_FOUND
+        );
+    }
+
+    #[tokio::test]
+    async fn export_should_stream_all_links() {
+        let app = test_app().await;
+        let id = shorten_url(&app, "https://www.rust-lang.org/").await;
+        shorten_url(&app, "https://docs.rs/").await;
+
+        let res = send(&app, "GET", "/export?format=csv", ALICE_KEY, Body::empty()).await;
+        assert_eq!(res.headers()["content-type"], "text/csv");
+        let csv = body_string(res).await;
+        assert_eq!(csv.lines().count(), 3);
+        assert!(csv.starts_with("id,short_url,url,"));
+        assert!(csv.contains(&format!("{},", id)));
+
+        let res = send(&app, "GET", "/export", BOB_KEY, Body::empty()).await;
+        assert!(body_bytes(res).await.is_empty());
+    }
+}
@@ -0,0 +1,739 @@
+mod auth;
+mod blocklist;
+mod bulk;
+mod cache;
+mod cli;
+mod config;
+mod error;
+mod ids;
+mod limit;
+mod links;
+mod metrics;
+mod preview;
+mod qr;
+mod store;
+#[cfg(test)]
+mod test_util;
+mod validate;
+
+use anyhow::Result;
+use auth::{ApiKeys, Owner};
+use axum::extract::{ConnectInfo, DefaultBodyLimit, Path, State};
+use axum::middleware::from_fn_with_state;
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, patch, post};
+use axum::{Json, Router};
+use blocklist::Blocklist;
+use cache::UrlCache;
+use chrono::{DateTime, Utc};
+use clap::Parser;
+use cli::{Cli, Command};
+use config::AppConfig;
+use error::{AppError, AppJson, AppQuery};
+use http::header::{LOCATION, REFERER, USER_AGENT};
+use http::{HeaderMap, HeaderValue, StatusCode};
+use ids::IdGenerator;
+use limit::RateLimiter;
+use metrics::Metrics;
+use serde::{Deserialize, Serialize};
+use std::fmt::Debug;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use store::{Click, Limits, NewUrl, UrlRecord, UrlStore};
+use tokio::net::TcpListener;
+use tokio::sync::mpsc;
+use tracing::{info, warn};
+use tracing_subscriber::filter::LevelFilter;
+use tracing_subscriber::fmt::Layer;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::Layer as _;
+use url::Url;
+
+const PURGE_INTERVAL: Duration = Duration::from_secs(60);
+const BLOCKLIST_RELOAD_INTERVAL: Duration = Duration::from_secs(10);
+const CLICK_QUEUE_SIZE: usize = 4096;
+const TOP_REFERRERS: i64 = 10;
+const MAX_ID_ATTEMPTS: usize = 20;
+
+#[derive(Debug, Deserialize)]
+struct ShortenReq {
+    url: String,
+    alias: Option<String>,
+    expires_at: Option<DateTime<Utc>>,
+    max_visits: Option<i64>,
+    #[serde(default)]
+    interstitial: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct ShortenRes {
+    url: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct RedirectReq {
+    /// Show where the link goes instead of following it, like `GET /:id+`.
+    preview: Option<String>,
+    /// Skips the interstitial page.
+    confirm: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct StatsReq {
+    #[serde(default = "default_stats_days")]
+    days: i64,
+}
+
+struct AppState {
+    store: Arc<dyn UrlStore>,
+    cache: UrlCache,
+    ids: Box<dyn IdGenerator>,
+    shorten_limit: RateLimiter,
+    redirect_limit: RateLimiter,
+    /// Counts the items of `POST /bulk`, the request itself counts against `shorten_limit`.
+    bulk_item_limit: RateLimiter,
+    blocklist: Blocklist,
+    metrics: Metrics,
+    clicks: mpsc::Sender<Click>,
+    api_keys: ApiKeys,
+    config: AppConfig,
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    // logs go to stderr so `export` and friends can be piped
+    let layer = Layer::new()
+        .with_ansi(true)
+        .with_writer(std::io::stderr)
+        .with_filter(LevelFilter::INFO);
+    tracing_subscriber::registry().with(layer).init();
+
+    let cli = Cli::parse();
+    let config = AppConfig::load()?;
+    if cli.migrate_only {
+        // lets deploy pipelines migrate before rolling out new servers
+        let store = store::connect(&config.db_url, config.pool_size).await?;
+        migrate(store.as_ref()).await?;
+        return Ok(());
+    }
+
+    match cli.command.unwrap_or(Command::Serve) {
+        Command::Serve => serve(config).await,
+        command => {
+            let state = AppState::try_new(config).await?;
+            cli::run(&state, command, &mut std::io::stdout().lock()).await
+        }
+    }
+}
+
+async fn serve(config: AppConfig) -> Result<()> {
+    let listener = TcpListener::bind(config.listen_addr).await?;
+    info!(
+        "Listening on {}, serving {}",
+        config.listen_addr, config.base_url
+    );
+
+    let state = AppState::try_new(config).await?;
+    info!(
+        "Using {} store with {} ids and {} api keys",
+        state.store.name(),
+        state.ids.name(),
+        state.api_keys.len()
+    );
+    let state = Arc::new(state);
+    spawn_purge_task(Arc::clone(&state));
+    if state.blocklist.is_enabled() {
+        info!("Blocking {} domains", state.blocklist.len());
+        spawn_blocklist_reloader(Arc::clone(&state));
+    }
+
+    let router = app(state).into_make_service_with_connect_info::<SocketAddr>();
+    axum::serve(listener, router).await?;
+
+    Ok(())
+}
+
+fn app(state: Arc<AppState>) -> Router {
+    let shorten_routes = Router::new()
+        .route("/", post(shorten))
+        .route(
+            "/bulk",
+            post(bulk::bulk).layer(DefaultBodyLimit::max(bulk::BODY_LIMIT)),
+        )
+        .route_layer(from_fn_with_state(Arc::clone(&state), limit::shorten));
+    let redirect_routes = Router::new()
+        .route("/:id", get(redirect))
+        .route("/:id/stats", get(stats))
+        .route("/:id/qr", get(qr::qr))
+        .route_layer(from_fn_with_state(Arc::clone(&state), limit::redirect));
+
+    Router::new()
+        .merge(shorten_routes)
+        .merge(redirect_routes)
+        .route("/admin/cache", get(cache_stats))
+        .route("/export", get(links::export))
+        .route("/links", get(links::list))
+        .route("/links/:id", patch(links::update).delete(links::delete))
+        .route("/healthz", get(metrics::healthz))
+        .route("/readyz", get(metrics::readyz))
+        .route("/metrics", get(metrics::metrics))
+        .route_layer(from_fn_with_state(Arc::clone(&state), metrics::track))
+        .layer(axum::middleware::from_fn(error::request_id))
+        .with_state(state)
+}
+
+async fn migrate(store: &dyn UrlStore) -> Result<()> {
+    let applied = store.migrate().await?;
+    if applied.is_empty() {
+        info!("{} schema is up to date", store.name());
+    }
+    for migration in applied {
+        info!("Applied {} migration {}", store.name(), migration);
+    }
+    Ok(())
+}
+
+fn spawn_click_recorder(store: Arc<dyn UrlStore>, mut rx: mpsc::Receiver<Click>) {
+    tokio::spawn(async move {
+        while let Some(click) = rx.recv().await {
+            if let Err(e) = store.record_click(&click).await {
+                warn!("Failed to record click on {}: {}", click.id, e);
+            }
+        }
+    });
+}
+
+fn spawn_purge_task(state: Arc<AppState>) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(PURGE_INTERVAL);
+        loop {
+            interval.tick().await;
+            match state.store.purge_expired(Utc::now()).await {
+                Ok(0) => {}
+                Ok(n) => info!("Purged {} expired urls", n),
+                Err(e) => warn!("Failed to purge expired urls: {}", e),
+            }
+            state.shorten_limit.purge();
+            state.redirect_limit.purge();
+            state.bulk_item_limit.purge();
+        }
+    });
+}
+
+fn spawn_blocklist_reloader(state: Arc<AppState>) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(BLOCKLIST_RELOAD_INTERVAL);
+        loop {
+            interval.tick().await;
+            match state.blocklist.reload_if_changed() {
+                Ok(false) => {}
+                Ok(true) => info!(
+                    "Reloaded blocklist, blocking {} domains",
+                    state.blocklist.len()
+                ),
+                // keep the previous list rather than unblocking everything
+                Err(e) => warn!("Failed to reload blocklist: {:#}", e),
+            }
+        }
+    });
+}
+
+async fn shorten(
+    State(state): State<Arc<AppState>>,
+    Owner(owner): Owner,
+    AppJson(data): AppJson<ShortenReq>,
+) -> Result<impl IntoResponse, AppError> {
+    let new = state.new_url(&owner, data, Utc::now()).await?;
+    let record = state.insert_url(new).await?;
+
+    let body = Json(ShortenRes {
+        url: state.config.short_url(&record.id),
+    });
+
+    Ok((StatusCode::CREATED, body))
+}
+
+async fn redirect(
+    Path(id): Path<String>,
+    AppQuery(query): AppQuery<RedirectReq>,
+    State(state): State<Arc<AppState>>,
+    peer: Option<ConnectInfo<SocketAddr>>,
+    req_headers: HeaderMap,
+) -> Result<Response, AppError> {
+    if let Some(id) = id.strip_suffix('+') {
+        return preview::preview(&state, id, &req_headers).await;
+    }
+    if is_set(&query.preview) {
+        return preview::preview(&state, &id, &req_headers).await;
+    }
+
+    let record = state.get_url(&id).await?;
+    if record.interstitial && !is_set(&query.confirm) {
+        return Ok(preview::interstitial(&state, &record));
+    }
+    state.take_visit(&record).await?;
+    state.metrics.redirects.inc();
+    let url = record.url;
+    state.record_click(Click {
+        id: id.clone(),
+        clicked_at: Utc::now(),
+        referer: header_str(&req_headers, REFERER.as_str()),
+        user_agent: header_str(&req_headers, USER_AGENT.as_str()),
+        ip: client_ip(
+            &req_headers,
+            peer.map(|ConnectInfo(addr)| addr),
+            state.config.trust_proxy,
+        ),
+    });
+
+    // urls are validated on the way in, but don't trust what comes out of the db
+    let location = HeaderValue::from_str(&url).map_err(|e| {
+        warn!("Invalid stored url for {}: {}", id, e);
+        AppError::Internal
+    })?;
+    let mut headers = http::header::HeaderMap::new();
+    headers.insert(LOCATION, location);
+
+    Ok((state.config.redirect_status(), headers).into_response())
+}
+
+/// Clicks of one of the owner's links, other owners' links are reported as unknown.
+async fn stats(
+    Path(id): Path<String>,
+    AppQuery(query): AppQuery<StatsReq>,
+    State(state): State<Arc<AppState>>,
+    Owner(owner): Owner,
+) -> Result<impl IntoResponse, AppError> {
+    // make unknown ids 404 instead of returning empty stats
+    let record = state.store.get_url(&id).await?;
+    if record.owner.as_deref() != Some(owner.as_str()) {
+        return Err(AppError::IdNotFound);
+    }
+
+    let since = (Utc::now() - chrono::Duration::days(query.days.clamp(1, 366)))
+        .date_naive()
+        .and_time(chrono::NaiveTime::MIN)
+        .and_utc();
+    let stats = state.store.stats(&id, since, TOP_REFERRERS).await?;
+
+    Ok(Json(stats))
+}
+
+async fn cache_stats(
+    State(state): State<Arc<AppState>>,
+    _: Owner,
+) -> Result<impl IntoResponse, AppError> {
+    Ok(Json(state.cache.stats()))
+}
+
+fn default_stats_days() -> i64 {
+    30
+}
+
+/// Query flags count as set unless they are `0` or `false`, so `?preview` works too.
+fn is_set(flag: &Option<String>) -> bool {
+    flag.as_deref().is_some_and(|v| v != "0" && v != "false")
+}
+
+fn header_str(headers: &HeaderMap, name: &str) -> Option<String> {
+    headers
+        .get(name)
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.to_string())
+}
+
+/// The last hop of `X-Forwarded-For` when behind a trusted proxy, otherwise the peer address.
+/// Earlier hops come from the client and can't be trusted.
+fn client_ip(headers: &HeaderMap, peer: Option<SocketAddr>, trust_proxy: bool) -> Option<String> {
+    trust_proxy
+        .then(|| header_str(headers, "x-forwarded-for"))
+        .flatten()
+        .and_then(|v| v.rsplit(',').next().map(|ip| ip.trim().to_string()))
+        .filter(|ip| !ip.is_empty())
+        .or_else(|| peer.map(|addr| addr.ip().to_string()))
+}
+
+impl AppState {
+    async fn try_new(config: AppConfig) -> Result<Self> {
+        let store = store::connect(&config.db_url, config.pool_size).await?;
+        migrate(store.as_ref()).await?;
+        let api_keys = config.api_keys.parse()?;
+        let (tx, rx) = mpsc::channel(CLICK_QUEUE_SIZE);
+        spawn_click_recorder(Arc::clone(&store), rx);
+        let cache = UrlCache::new(
+            config.cache_capacity,
+            config.cache_ttl(),
+            config.negative_cache_ttl(),
+        );
+
+        let blocklist = Blocklist::load(&config.blocklist_file)?;
+
+        Ok(Self {
+            store,
+            cache,
+            ids: ids::from_config(&config),
+            shorten_limit: RateLimiter::new(config.shorten_rate_limit, config.shorten_burst),
+            redirect_limit: RateLimiter::new(config.redirect_rate_limit, config.redirect_burst),
+            bulk_item_limit: RateLimiter::new(
+                config.bulk_rate_limit,
+                u32::try_from(config.bulk_limit).unwrap_or(u32::MAX),
+            ),
+            blocklist,
+            metrics: Metrics::try_new()?,
+            clicks: tx,
+            api_keys,
+            config,
+        })
+    }
+
+    /// Queues the click for the recorder task, a full queue drops it rather than
+    /// slowing down the redirect.
+    fn record_click(&self, click: Click) {
+        if let Err(e) = self.clicks.try_send(click) {
+            warn!("Failed to queue click: {}", e);
+        }
+    }
+
+    /// Resolves `id` for a redirect, preview or qr code, expired or used up urls are gone.
+    async fn get_url(&self, id: &str) -> Result<UrlRecord, AppError> {
+        let record = self.cache.get_url(self.store.as_ref(), id).await?;
+        if record.is_expired(Utc::now()) || record.is_exhausted() {
+            return Err(AppError::Gone);
+        }
+        Ok(record)
+    }
+
+    /// Counts a redirect against `max_visits`, the url is gone once they are used up.
+    async fn take_visit(&self, record: &UrlRecord) -> Result<(), AppError> {
+        if record.max_visits.is_none() {
+            return Ok(());
+        }
+        if !self.store.take_visit(&record.id, Utc::now()).await? {
+            return Err(AppError::Gone);
+        }
+        // the cached record has the old visit count
+        self.cache.invalidate(&record.id);
+        Ok(())
+    }
+
+    /// Inserts a url built by `new_url`, a taken generated id is retried with a new one.
+    async fn insert_url(&self, mut new: NewUrl) -> Result<UrlRecord, AppError> {
+        for attempt in 1..=MAX_ID_ATTEMPTS {
+            match self.store.insert_url(&new).await {
+                Ok(record) => {
+                    // the id may have been probed and cached as missing before
+                    self.cache.invalidate(&record.id);
+                    if record.inserted {
+                        self.metrics.shortened.inc();
+                    }
+                    return Ok(record);
+                }
+                Err(AppError::IdExists) if new.custom => return Err(AppError::AliasTaken),
+                Err(AppError::IdExists) => {
+                    self.metrics.id_collisions.inc();
+                    new.id = self.generate_id(&new.url, attempt).await?;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        warn!("Failed to find a free id for {}", new.url);
+        Err(AppError::IdSpaceExhausted)
+    }
+
+    /// Validates and inserts a batch, one result per item in order. Items that collide on a
+    /// generated id are retried with a new id in a follow-up batch. Once any item is committed
+    /// later failures are reported per item, so the caller still learns what was created.
+    async fn insert_urls(
+        &self,
+        owner: &str,
+        reqs: Vec<Result<ShortenReq, AppError>>,
+    ) -> Result<Vec<Result<UrlRecord, AppError>>, AppError> {
+        let now = Utc::now();
+        // slots still pending after the last attempt stay exhausted
+        let mut results: Vec<_> = (0..reqs.len())
+            .map(|_| Err(AppError::IdSpaceExhausted))
+            .collect();
+        let mut indices = Vec::with_capacity(reqs.len());
+        let mut news = Vec::with_capacity(reqs.len());
+        for (i, req) in reqs.into_iter().enumerate() {
+            let new = match req {
+                Ok(req) => self.new_url(owner, req, now).await,
+                Err(e) => Err(e),
+            };
+            match new {
+                Ok(new) => {
+                    indices.push(i);
+                    news.push(new);
+                }
+                Err(e) => results[i] = Err(e),
+            }
+        }
+
+        let mut committed = false;
+        for attempt in 1..=MAX_ID_ATTEMPTS {
+            if news.is_empty() {
+                break;
+            }
+            let inserted = match self.store.insert_urls(&news).await {
+                Ok(inserted) => inserted,
+                // nothing was created, so the request can fail as a whole
+                Err(e) if !committed => return Err(e),
+                Err(e) => {
+                    for i in indices {
+                        results[i] = Err(e.clone());
+                    }
+                    break;
+                }
+            };
+            let (mut retry_indices, mut retry_news) = (vec![], vec![]);
+            for ((i, mut new), ret) in indices.into_iter().zip(news).zip(inserted) {
+                match ret {
+                    Err(AppError::IdExists) if !new.custom => {
+                        self.metrics.id_collisions.inc();
+                        match self.generate_id(&new.url, attempt).await {
+                            Ok(id) => {
+                                new.id = id;
+                                retry_indices.push(i);
+                                retry_news.push(new);
+                            }
+                            Err(e) => results[i] = Err(e),
+                        }
+                    }
+                    Err(AppError::IdExists) => results[i] = Err(AppError::AliasTaken),
+                    ret => {
+                        if let Ok(record) = &ret {
+                            self.cache.invalidate(&record.id);
+                            // an existing link handed back for the same url isn't a write
+                            if record.inserted {
+                                committed = true;
+                                self.metrics.shortened.inc();
+                            }
+                        }
+                        results[i] = ret;
+                    }
+                }
+            }
+            (indices, news) = (retry_indices, retry_news);
+        }
+
+        Ok(results)
+    }
+
+    async fn new_url(
+        &self,
+        owner: &str,
+        req: ShortenReq,
+        now: DateTime<Utc>,
+    ) -> Result<NewUrl, AppError> {
+        if let Some(alias) = &req.alias {
+            validate::validate_alias(alias)?;
+        }
+        let url = self.check_url(&req.url)?;
+        let limits = Limits {
+            expires_at: req.expires_at,
+            max_visits: req.max_visits,
+        };
+        validate::validate_limits(&limits, now)?;
+
+        let (id, custom) = match req.alias {
+            Some(alias) => (alias, true),
+            None => (self.generate_id(&url, 0).await?, false),
+        };
+        Ok(NewUrl {
+            id,
+            url,
+            owner: owner.to_string(),
+            custom,
+            limits,
+            interstitial: req.interstitial,
+        })
+    }
+
+    /// Normalizes `raw` and rejects links to blocked domains.
+    fn check_url(&self, raw: &str) -> Result<String, AppError> {
+        let url = validate::normalize_url(raw, &self.config.base_url)?;
+        let host = Url::parse(&url)
+            .ok()
+            .and_then(|u| u.host_str().map(|h| h.to_string()))
+            .unwrap_or_default();
+        if self.blocklist.is_blocked(&host) {
+            return Err(AppError::BlockedDomain(host));
+        }
+        Ok(url)
+    }
+
+    /// A candidate id for `url`, reserved words count as collisions.
+    async fn generate_id(&self, url: &str, mut attempt: usize) -> Result<String, AppError> {
+        loop {
+            let id = self.ids.generate(self.store.as_ref(), url, attempt).await?;
+            if !validate::is_reserved(&id) {
+                return Ok(id);
+            }
+            attempt += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::*;
+    use axum::body::Body;
+    use tower::ServiceExt;
+
+    #[tokio::test]
+    async fn shorten_and_redirect_should_work() {
+        let app = test_app().await;
+        let id = shorten_url(&app, "https://www.rust-lang.org/").await;
+        assert_eq!(id.len(), 6);
+
+        let res = fetch(&app, &format!("/{}", id)).await;
+        assert_eq!(res.status(), StatusCode::PERMANENT_REDIRECT);
+        assert_eq!(res.headers()[LOCATION], "https://www.rust-lang.org/");
+    }
+
+    #[tokio::test]
+    async fn shorten_same_url_should_return_same_id() {
+        let app = test_app().await;
+        let id1 = shorten_url(&app, "https://www.rust-lang.org/").await;
+        let id2 = shorten_url(&app, "https://www.rust-lang.org/").await;
+        assert_eq!(id1, id2);
+    }
+
+    #[tokio::test]
+    async fn redirect_unknown_id_should_404() {
+        let app = test_app().await;
+        let req = http::Request::get("/nope42")
+            .header(error::REQUEST_ID_HEADER, "req-1")
+            .body(Body::empty())
+            .unwrap();
+        let res = app.oneshot(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+
+        let body = body_json(res).await;
+        assert_eq!(body["code"], "not_found");
+        assert_eq!(body["request_id"], "req-1");
+    }
+
+    #[tokio::test]
+    async fn shorten_with_alias_should_work() {
+        let app = test_app().await;
+        let url = "https://www.rust-lang.org/";
+        let id = shorten_url(&app, url).await;
+
+        let res = post_shorten(&app, serde_json::json!({ "url": url, "alias": "rust" })).await;
+        assert_eq!(res.status(), StatusCode::CREATED);
+        // aliases never reuse the generated id of the same url
+        assert_ne!(id, "rust");
+
+        let res = post_shorten(&app, serde_json::json!({ "url": url, "alias": "rust" })).await;
+        assert_eq!(res.status(), StatusCode::CONFLICT);
+
+        let res = post_shorten(&app, serde_json::json!({ "url": url, "alias": "Admin" })).await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+
+        let res = post_shorten(&app, serde_json::json!({ "url": url, "alias": "a/b" })).await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn redirect_past_max_visits_should_410() {
+        let app = test_app().await;
+        let res = post_shorten(
+            &app,
+            serde_json::json!({ "url": "https://www.rust-lang.org/", "alias": "once", "max_visits": 1 }),
+        )
+        .await;
+        assert_eq!(res.status(), StatusCode::CREATED);
+
+        for status in [StatusCode::PERMANENT_REDIRECT, StatusCode::GONE] {
+            assert_eq!(fetch(&app, "/once").await.status(), status);
+        }
+    }
+
+    #[tokio::test]
+    async fn redirect_past_expires_at_should_410() {
+        // the api rejects past expiry times, so put the url in the store directly
+        let state = Arc::new(test_state().await);
+        let new = NewUrl {
+            id: "old".into(),
+            url: "https://www.rust-lang.org/".into(),
+            owner: "alice".into(),
+            custom: true,
+            limits: Limits {
+                expires_at: Some(Utc::now() - chrono::Duration::seconds(1)),
+                max_visits: None,
+            },
+            interstitial: false,
+        };
+        state.store.insert_url(&new).await.unwrap();
+
+        let app = app(state);
+        assert_eq!(fetch(&app, "/old").await.status(), StatusCode::GONE);
+        assert_eq!(fetch(&app, "/old+").await.status(), StatusCode::GONE);
+    }
+
+    #[tokio::test]
+    async fn stats_should_count_clicks_by_day_and_referrer() {
+        let state = Arc::new(test_state().await);
+        let app = app(Arc::clone(&state));
+        let id = shorten_url(&app, "https://www.rust-lang.org/").await;
+
+        for referer in ["https://a.com/", "https://b.com/", "https://b.com/", ""] {
+            let mut req = http::Request::get(format!("/{}", id));
+            if !referer.is_empty() {
+                req = req.header(REFERER, referer);
+            }
+            let res = app.clone().oneshot(req.body(Body::empty()).unwrap()).await;
+            assert_eq!(res.unwrap().status(), StatusCode::PERMANENT_REDIRECT);
+        }
+        let old = Click {
+            id: id.clone(),
+            clicked_at: Utc::now() - chrono::Duration::days(3),
+            referer: Some("https://a.com/".into()),
+            user_agent: None,
+            ip: None,
+        };
+        state.store.record_click(&old).await.unwrap();
+        // clicks of redirects are recorded in the background
+        wait_for_clicks(&state, &id, 5).await;
+
+        let uri = format!("/{}/stats?days=2", id);
+        let stats = body_json(send(&app, "GET", &uri, ALICE_KEY, Body::empty()).await).await;
+        assert_eq!(stats["total"], 5);
+        assert_eq!(stats["daily"].as_array().unwrap().len(), 1);
+        assert_eq!(stats["daily"][0]["clicks"], 4);
+        assert_eq!(
+            stats["top_referrers"],
+            serde_json::json!([
+                { "referer": "https://a.com/", "clicks": 2 },
+                { "referer": "https://b.com/", "clicks": 2 },
+            ])
+        );
+
+        let uri = format!("/{}/stats?days=7", id);
+        let stats = body_json(send(&app, "GET", &uri, ALICE_KEY, Body::empty()).await).await;
+        let daily: Vec<_> = stats["daily"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|d| d["clicks"].as_i64().unwrap())
+            .collect();
+        assert_eq!(daily, [1, 4]);
+
+        // only the owner sees who links to it
+        let res = send(&app, "GET", &uri, BOB_KEY, Body::empty()).await;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+        assert_eq!(fetch(&app, &uri).await.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn shorten_without_valid_key_should_401() {
+        let app = test_app().await;
+        let body = serde_json::json!({ "url": "https://www.rust-lang.org/" }).to_string();
+        let res = send(&app, "POST", "/", "wrong", Body::from(body)).await;
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+    }
+}
@@ -0,0 +1,164 @@
+use crate::error::AppError;
+use crate::store::PoolStats;
+use crate::AppState;
+use axum::extract::{MatchedPath, Request, State};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use http::header::CONTENT_TYPE;
+use prometheus::{
+    Encoder, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge, Opts, Registry,
+    TextEncoder,
+};
+use serde_json::json;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tracing::warn;
+
+const READY_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Prometheus metrics of one app, on their own registry so every `AppState` starts at zero.
+pub struct Metrics {
+    registry: Registry,
+    requests: IntCounterVec,
+    latency: HistogramVec,
+    pub shortened: IntCounter,
+    pub redirects: IntCounter,
+    /// Generated ids that were taken and had to be retried.
+    pub id_collisions: IntCounter,
+    pool_connections: IntGauge,
+    pool_idle: IntGauge,
+    pool_max: IntGauge,
+}
+
+impl Metrics {
+    pub fn try_new() -> prometheus::Result<Self> {
+        let registry = Registry::new_custom(Some("shortener".into()), None)?;
+        let requests = IntCounterVec::new(
+            Opts::new("http_requests_total", "HTTP requests by route and status"),
+            &["method", "route", "status"],
+        )?;
+        let latency = HistogramVec::new(
+            HistogramOpts::new(
+                "http_request_duration_seconds",
+                "HTTP request latency by route",
+            ),
+            &["method", "route"],
+        )?;
+        let shortened = IntCounter::new("shortened_total", "Urls shortened")?;
+        let redirects = IntCounter::new("redirects_total", "Links followed")?;
+        let id_collisions = IntCounter::new("id_collisions_total", "Generated ids retried")?;
+        let pool_connections = IntGauge::new("db_pool_connections", "Open db connections")?;
+        let pool_idle = IntGauge::new("db_pool_idle_connections", "Idle db connections")?;
+        let pool_max = IntGauge::new("db_pool_max_connections", "Max db connections")?;
+
+        registry.register(Box::new(requests.clone()))?;
+        registry.register(Box::new(latency.clone()))?;
+        registry.register(Box::new(shortened.clone()))?;
+        registry.register(Box::new(redirects.clone()))?;
+        registry.register(Box::new(id_collisions.clone()))?;
+        registry.register(Box::new(pool_connections.clone()))?;
+        registry.register(Box::new(pool_idle.clone()))?;
+        registry.register(Box::new(pool_max.clone()))?;
+
+        Ok(Self {
+            registry,
+            requests,
+            latency,
+            shortened,
+            redirects,
+            id_collisions,
+            pool_connections,
+            pool_idle,
+            pool_max,
+        })
+    }
+
+    fn encode(&self, pool: Option<PoolStats>) -> Result<String, AppError> {
+        if let Some(pool) = pool {
+            self.pool_connections.set(pool.connections as i64);
+            self.pool_idle.set(pool.idle as i64);
+            self.pool_max.set(pool.max as i64);
+        }
+        let mut buf = vec![];
+        TextEncoder::new()
+            .encode(&self.registry.gather(), &mut buf)
+            .map_err(|e| {
+                warn!("Failed to encode metrics: {}", e);
+                AppError::Internal
+            })?;
+        String::from_utf8(buf).map_err(|_| AppError::Internal)
+    }
+}
+
+/// Counts and times requests by their route template, so ids don't blow up the label set.
+pub async fn track(
+    State(state): State<Arc<AppState>>,
+    path: MatchedPath,
+    req: Request,
+    next: Next,
+) -> Response {
+    // a route layer, so only requests that matched a route get here
+    let route = path.as_str().to_string();
+    let method = req.method().to_string();
+    let start = Instant::now();
+
+    let res = next.run(req).await;
+
+    state
+        .metrics
+        .latency
+        .with_label_values(&[&method, &route])
+        .observe(start.elapsed().as_secs_f64());
+    state
+        .metrics
+        .requests
+        .with_label_values(&[&method, &route, res.status().as_str()])
+        .inc();
+    res
+}
+
+pub async fn metrics(State(state): State<Arc<AppState>>) -> Result<impl IntoResponse, AppError> {
+    let body = state.metrics.encode(state.store.pool_stats())?;
+    Ok((
+        [(CONTENT_TYPE, TextEncoder::new().format_type().to_string())],
+        body,
+    ))
+}
+
+/// Liveness, the process is up and serving requests.
+pub async fn healthz() -> impl IntoResponse {
+    Json(json!({ "status": "ok" }))
+}
+
+/// Readiness, the store answers in time.
+pub async fn readyz(State(state): State<Arc<AppState>>) -> Result<impl IntoResponse, AppError> {
+    tokio::time::timeout(READY_TIMEOUT, state.store.ping())
+        .await
+        .map_err(|_| AppError::DbUnavailable)??;
+    Ok(Json(json!({ "status": "ok", "store": state.store.name() })))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_util::*;
+    use http::StatusCode;
+
+    #[tokio::test]
+    async fn health_and_metrics_should_work() {
+        let app = test_app().await;
+        for uri in ["/healthz", "/readyz"] {
+            assert_eq!(fetch(&app, uri).await.status(), StatusCode::OK);
+        }
+
+        let id = shorten_url(&app, "https://www.rust-lang.org/").await;
+        // returns the existing link, nothing new is shortened
+        shorten_url(&app, "https://www.rust-lang.org/").await;
+        fetch(&app, &format!("/{}", id)).await;
+
+        let metrics = body_string(fetch(&app, "/metrics").await).await;
+        assert!(metrics.contains("shortener_shortened_total 1"));
+        assert!(metrics.contains("shortener_redirects_total 1"));
+        assert!(metrics.contains(r#"route="/:id",status="308""#));
+    }
+}
@@ -0,0 +1,6 @@
+-- the original schema, kept as-is so databases created before migrations were introduced
+-- are picked up without changes
+CREATE TABLE IF NOT EXISTS urls (
+    id CHAR(6) PRIMARY KEY,
+    url TEXT NOT NULL UNIQUE
+);
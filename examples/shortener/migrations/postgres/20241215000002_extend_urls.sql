@@ -0,0 +1,14 @@
+-- room for aliases and longer generated ids
+ALTER TABLE urls ALTER COLUMN id TYPE VARCHAR(32);
+
+-- urls are deduplicated per owner through dedup_key now, aliases and limited links share urls
+ALTER TABLE urls DROP CONSTRAINT IF EXISTS urls_url_key;
+
+ALTER TABLE urls ADD COLUMN IF NOT EXISTS owner TEXT;
+ALTER TABLE urls ADD COLUMN IF NOT EXISTS created_at TIMESTAMPTZ NOT NULL DEFAULT now();
+ALTER TABLE urls ADD COLUMN IF NOT EXISTS dedup_key TEXT UNIQUE;
+ALTER TABLE urls ADD COLUMN IF NOT EXISTS expires_at TIMESTAMPTZ;
+ALTER TABLE urls ADD COLUMN IF NOT EXISTS max_visits BIGINT;
+ALTER TABLE urls ADD COLUMN IF NOT EXISTS visits BIGINT NOT NULL DEFAULT 0;
+
+CREATE INDEX IF NOT EXISTS urls_owner_idx ON urls (owner, id);
@@ -0,0 +1,10 @@
+CREATE TABLE IF NOT EXISTS clicks (
+    id BIGSERIAL PRIMARY KEY,
+    url_id VARCHAR(32) NOT NULL REFERENCES urls(id) ON DELETE CASCADE,
+    clicked_at TIMESTAMPTZ NOT NULL,
+    referer TEXT,
+    user_agent TEXT,
+    ip TEXT
+);
+
+CREATE INDEX IF NOT EXISTS clicks_url_id_idx ON clicks (url_id, clicked_at);
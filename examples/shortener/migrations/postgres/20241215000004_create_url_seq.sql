@@ -0,0 +1,2 @@
+-- integer keys of sequential and sqids ids
+CREATE SEQUENCE IF NOT EXISTS url_seq;
@@ -0,0 +1,12 @@
+CREATE TABLE IF NOT EXISTS urls (
+    id VARCHAR(32) PRIMARY KEY,
+    url TEXT NOT NULL,
+    owner TEXT,
+    created_at TEXT NOT NULL,
+    dedup_key TEXT UNIQUE,
+    expires_at TEXT,
+    max_visits BIGINT,
+    visits BIGINT NOT NULL DEFAULT 0
+);
+
+CREATE INDEX IF NOT EXISTS urls_owner_idx ON urls (owner, id);
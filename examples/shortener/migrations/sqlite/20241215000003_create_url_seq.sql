@@ -0,0 +1,6 @@
+-- integer keys of sequential and sqids ids, sqlite has no sequences so keep a single row
+CREATE TABLE IF NOT EXISTS url_seq (
+    value INTEGER NOT NULL
+);
+
+INSERT INTO url_seq (value) SELECT 0 WHERE NOT EXISTS (SELECT 1 FROM url_seq);
@@ -0,0 +1,170 @@
+use crate::error::AppError;
+use crate::store::UrlRecord;
+use crate::AppState;
+use axum::response::{Html, IntoResponse, Response};
+use axum::Json;
+use chrono::{DateTime, Utc};
+use http::header::{ACCEPT, CACHE_CONTROL};
+use http::HeaderMap;
+use serde::Serialize;
+
+#[derive(Debug, Serialize)]
+pub struct PreviewRes {
+    short_url: String,
+    url: String,
+    created_at: DateTime<Utc>,
+    expires_at: Option<DateTime<Utc>>,
+    clicks: i64,
+    interstitial: bool,
+}
+
+/// Shows where `id` goes without following it or counting a visit, as JSON when the
+/// client accepts it and as a page otherwise.
+pub async fn preview(
+    state: &AppState,
+    id: &str,
+    headers: &HeaderMap,
+) -> Result<Response, AppError> {
+    let record = state.get_url(id).await?;
+    let res = PreviewRes {
+        short_url: state.config.short_url(&record.id),
+        clicks: state.store.count_clicks(&record.id).await?,
+        url: record.url,
+        created_at: record.created_at,
+        expires_at: record.expires_at,
+        interstitial: record.interstitial,
+    };
+
+    let accepts_json = headers
+        .get(ACCEPT)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.contains("application/json"));
+    if accepts_json {
+        return Ok(Json(res).into_response());
+    }
+
+    let expires = res
+        .expires_at
+        .map(|t| format!("<dt>Expires</dt><dd>{}</dd>", t.to_rfc3339()))
+        .unwrap_or_default();
+    let body = format!(
+        r#"<h1>Where does {short} go?</h1>
+<dl>
+<dt>Destination</dt><dd><a href="{url}" rel="nofollow noopener noreferrer">{url}</a></dd>
+<dt>Created</dt><dd>{created}</dd>{expires}
+<dt>Clicks</dt><dd>{clicks}</dd>
+</dl>"#,
+        short = escape(&res.short_url),
+        url = escape(&res.url),
+        created = res.created_at.to_rfc3339(),
+        expires = expires,
+        clicks = res.clicks,
+    );
+    Ok(page("Link preview", &body))
+}
+
+/// The confirmation page of links with `interstitial` set, continuing follows the short
+/// link again with `?confirm=1` so the visit is counted as usual.
+pub fn interstitial(state: &AppState, record: &UrlRecord) -> Response {
+    let body = format!(
+        r#"<h1>You are leaving {host}</h1>
+<p>This link goes to:</p>
+<p><code>{url}</code></p>
+<p><a href="{confirm}">Continue</a></p>"#,
+        host = escape(state.config.base_url.host_str().unwrap_or_default()),
+        url = escape(&record.url),
+        confirm = escape(&format!("{}?confirm=1", state.config.short_url(&record.id))),
+    );
+    page("Leaving the site", &body)
+}
+
+fn page(title: &str, body: &str) -> Response {
+    let html = format!(
+        r#"<!DOCTYPE html>
+<html>
+<head><meta charset="utf-8"><meta name="robots" content="noindex"><title>{}</title></head>
+<body>
+{}
+</body>
+</html>
+"#,
+        title, body
+    );
+    ([(CACHE_CONTROL, "no-store")], Html(html)).into_response()
+}
+
+fn escape(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_util::*;
+    use axum::body::Body;
+    use http::{Request, StatusCode};
+    use tower::ServiceExt;
+
+    #[tokio::test]
+    async fn preview_should_not_redirect() {
+        let app = test_app().await;
+        let id = shorten_url(&app, "https://www.rust-lang.org/?a=<b>").await;
+
+        let req = Request::get(format!("/{}+", id))
+            .header("accept", "application/json")
+            .body(Body::empty())
+            .unwrap();
+        let res = app.clone().oneshot(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        let body = body_json(res).await;
+        assert_eq!(body["url"], "https://www.rust-lang.org/?a=%3Cb%3E");
+
+        let res = fetch(&app, &format!("/{}?preview=1", id)).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert!(res.headers()["content-type"]
+            .to_str()
+            .unwrap()
+            .starts_with("text/html"));
+    }
+
+    #[tokio::test]
+    async fn interstitial_should_confirm_before_redirect() {
+        let app = test_app().await;
+        let body = serde_json::json!({ "url": "https://www.rust-lang.org/", "alias": "careful", "interstitial": true });
+        let res = post_shorten(&app, body).await;
+        assert_eq!(res.status(), StatusCode::CREATED);
+
+        let res = fetch(&app, "/careful").await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let page = body_string(res).await;
+        assert!(page.contains("https://www.rust-lang.org/"));
+        assert!(page.contains("/careful?confirm=1"));
+
+        let res = fetch(&app, "/careful?confirm=1").await;
+        assert_eq!(res.status(), StatusCode::PERMANENT_REDIRECT);
+    }
+
+    #[tokio::test]
+    async fn preview_of_used_up_link_should_410() {
+        let app = test_app().await;
+        let body =
+            serde_json::json!({ "url": "https://docs.rs/", "alias": "once", "max_visits": 1 });
+        assert_eq!(post_shorten(&app, body).await.status(), StatusCode::CREATED);
+        assert_eq!(fetch(&app, "/once+").await.status(), StatusCode::OK);
+
+        fetch(&app, "/once").await;
+        for uri in ["/once+", "/once?preview=1", "/once", "/once/qr"] {
+            assert_eq!(fetch(&app, uri).await.status(), StatusCode::GONE, "{}", uri);
+        }
+    }
+}
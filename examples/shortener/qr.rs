@@ -0,0 +1,262 @@
+use crate::error::{AppError, AppQuery};
+use crate::AppState;
+use axum::extract::{Path, State};
+use axum::response::{IntoResponse, Response};
+use http::header::{CACHE_CONTROL, CONTENT_TYPE, ETAG, IF_NONE_MATCH};
+use http::{HeaderMap, HeaderValue, StatusCode};
+use qrcode::{Color, EcLevel, QrCode};
+use serde::Deserialize;
+use std::fmt::Write;
+use std::sync::Arc;
+use tracing::warn;
+
+const MIN_SIZE: u32 = 64;
+const MAX_SIZE: u32 = 2048;
+const MAX_MARGIN: u32 = 16;
+
+#[derive(Debug, Deserialize)]
+pub struct QrReq {
+    #[serde(default)]
+    format: QrFormat,
+    /// Width of the image in pixels, rounded down to a whole number of pixels per module.
+    #[serde(default = "default_size")]
+    size: u32,
+    /// Quiet zone around the code, in modules.
+    #[serde(default = "default_margin")]
+    margin: u32,
+    #[serde(default)]
+    ecc: Ecc,
+}
+
+#[derive(Debug, Default, Clone, Copy, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum QrFormat {
+    #[default]
+    Png,
+    Svg,
+}
+
+#[derive(Debug, Default, Clone, Copy, Deserialize)]
+enum Ecc {
+    #[serde(alias = "l")]
+    L,
+    #[default]
+    #[serde(alias = "m")]
+    M,
+    #[serde(alias = "q")]
+    Q,
+    #[serde(alias = "h")]
+    H,
+}
+
+/// Dark/light modules of an encoded short url, including the margin.
+struct Modules {
+    width: u32,
+    dark: Vec<bool>,
+}
+
+pub async fn qr(
+    Path(id): Path<String>,
+    AppQuery(query): AppQuery<QrReq>,
+    State(state): State<Arc<AppState>>,
+    req_headers: HeaderMap,
+) -> Result<Response, AppError> {
+    if !(MIN_SIZE..=MAX_SIZE).contains(&query.size) {
+        return Err(AppError::InvalidRequest(format!(
+            "size must be between {} and {}",
+            MIN_SIZE, MAX_SIZE
+        )));
+    }
+    if query.margin > MAX_MARGIN {
+        return Err(AppError::InvalidRequest(format!(
+            "margin must be at most {}",
+            MAX_MARGIN
+        )));
+    }
+
+    let record = state.get_url(&id).await?;
+
+    // the image only depends on the short url and the options, never on the target
+    let short_url = state.config.short_url(&record.id);
+    let etag = query.etag(&short_url);
+    let mut headers = HeaderMap::new();
+    headers.insert(
+        ETAG,
+        HeaderValue::from_str(&etag).map_err(|_| AppError::Internal)?,
+    );
+    headers.insert(
+        CACHE_CONTROL,
+        HeaderValue::from_static("public, max-age=86400"),
+    );
+
+    let if_none_match = req_headers
+        .get(IF_NONE_MATCH)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or_default();
+    if if_none_match
+        .split(',')
+        .any(|tag| tag.trim() == "*" || tag.trim() == etag)
+    {
+        return Ok((StatusCode::NOT_MODIFIED, headers).into_response());
+    }
+
+    let modules = Modules::encode(&short_url, query.ecc.into(), query.margin)?;
+    let scale = (query.size / modules.width).max(1);
+    let (content_type, body) = match query.format {
+        QrFormat::Png => ("image/png", modules.to_png(scale)?),
+        QrFormat::Svg => ("image/svg+xml", modules.to_svg(scale).into_bytes()),
+    };
+    headers.insert(CONTENT_TYPE, HeaderValue::from_static(content_type));
+
+    Ok((headers, body).into_response())
+}
+
+fn default_size() -> u32 {
+    256
+}
+
+fn default_margin() -> u32 {
+    4
+}
+
+impl QrReq {
+    /// Hashes every option that changes the image, so the tag is stable across releases.
+    fn etag(&self, short_url: &str) -> String {
+        let format = match self.format {
+            QrFormat::Png => "png",
+            QrFormat::Svg => "svg",
+        };
+        let ecc = match self.ecc {
+            Ecc::L => "L",
+            Ecc::M => "M",
+            Ecc::Q => "Q",
+            Ecc::H => "H",
+        };
+        let key = format!(
+            "{}|{}|{}|{}|{}",
+            short_url, format, self.size, self.margin, ecc
+        );
+        format!("\"{}\"", &blake3::hash(key.as_bytes()).to_hex()[..16])
+    }
+}
+
+impl From<Ecc> for EcLevel {
+    fn from(ecc: Ecc) -> Self {
+        match ecc {
+            Ecc::L => EcLevel::L,
+            Ecc::M => EcLevel::M,
+            Ecc::Q => EcLevel::Q,
+            Ecc::H => EcLevel::H,
+        }
+    }
+}
+
+impl Modules {
+    fn encode(data: &str, ecc: EcLevel, margin: u32) -> Result<Self, AppError> {
+        let code = QrCode::with_error_correction_level(data, ecc)
+            .map_err(|e| AppError::InvalidRequest(format!("cannot encode qr code: {}", e)))?;
+        let inner = code.width() as u32;
+        let width = inner + 2 * margin;
+        let colors = code.to_colors();
+
+        let mut dark = vec![false; (width * width) as usize];
+        for (i, color) in colors.iter().enumerate() {
+            let (x, y) = (i as u32 % inner + margin, i as u32 / inner + margin);
+            dark[(y * width + x) as usize] = *color == Color::Dark;
+        }
+        Ok(Self { width, dark })
+    }
+
+    fn is_dark(&self, x: u32, y: u32) -> bool {
+        self.dark[(y * self.width + x) as usize]
+    }
+
+    fn to_png(&self, scale: u32) -> Result<Vec<u8>, AppError> {
+        let side = self.width * scale;
+        let mut pixels = Vec::with_capacity((side * side) as usize);
+        for y in 0..side {
+            for x in 0..side {
+                pixels.push(if self.is_dark(x / scale, y / scale) {
+                    0
+                } else {
+                    255
+                });
+            }
+        }
+
+        let mut buf = Vec::new();
+        let mut encoder = png::Encoder::new(&mut buf, side, side);
+        encoder.set_color(png::ColorType::Grayscale);
+        encoder.set_depth(png::BitDepth::Eight);
+        encoder
+            .write_header()
+            .and_then(|mut writer| writer.write_image_data(&pixels))
+            .map_err(|e| {
+                warn!("Failed to encode qr png: {}", e);
+                AppError::Internal
+            })?;
+        Ok(buf)
+    }
+
+    fn to_svg(&self, scale: u32) -> String {
+        let side = self.width * scale;
+        let mut path = String::new();
+        for y in 0..self.width {
+            for x in 0..self.width {
+                if self.is_dark(x, y) {
+                    let _ = write!(path, "M{} {}h1v1h-1z", x, y);
+                }
+            }
+        }
+        format!(
+            concat!(
+                r#"<?xml version="1.0" encoding="UTF-8"?>"#,
+                r#"<svg xmlns="http://www.w3.org/2000/svg" width="{side}" height="{side}" "#,
+                r#"viewBox="0 0 {w} {w}" shape-rendering="crispEdges">"#,
+                r##"<rect width="{w}" height="{w}" fill="#fff"/>"##,
+                r##"<path d="{path}" fill="#000"/></svg>"##
+            ),
+            side = side,
+            w = self.width,
+            path = path
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_util::*;
+    use axum::body::Body;
+    use http::{Request, StatusCode};
+    use tower::ServiceExt;
+
+    #[tokio::test]
+    async fn qr_should_render_and_honor_etag() {
+        let app = test_app().await;
+        let id = shorten_url(&app, "https://www.rust-lang.org/").await;
+
+        let res = fetch(&app, &format!("/{}/qr?size=128&ecc=H", id)).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers()["content-type"], "image/png");
+        let etag = res.headers()["etag"].clone();
+        assert!(body_bytes(res).await.starts_with(b"\x89PNG"));
+
+        let req = Request::get(format!("/{}/qr?size=128&ecc=H", id))
+            .header("if-none-match", etag.clone())
+            .body(Body::empty())
+            .unwrap();
+        let res = app.clone().oneshot(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::NOT_MODIFIED);
+
+        // the same options spelled differently give the same image
+        let res = fetch(&app, &format!("/{}/qr?margin=4&ecc=h&size=128", id)).await;
+        assert_eq!(res.headers()["etag"], etag);
+
+        let res = fetch(&app, &format!("/{}/qr?format=svg", id)).await;
+        assert_eq!(res.headers()["content-type"], "image/svg+xml");
+        assert_ne!(res.headers()["etag"], etag);
+
+        let res = fetch(&app, &format!("/{}/qr?size=1", id)).await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+    }
+}
@@ -0,0 +1,211 @@
+use super::{Click, DailyClicks, NewUrl, RefererClicks, Stats, UrlRecord, UrlStore};
+use crate::error::AppError;
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use dashmap::mapref::entry::Entry;
+use dashmap::DashMap;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicI64, Ordering};
+
+/// Keeps everything in process, handy for tests and local runs without a database.
+#[derive(Debug, Default)]
+pub struct MemoryStore {
+    ids: DashMap<String, UrlRecord>,
+    // dedup key -> id
+    dedup: DashMap<String, String>,
+    clicks: DashMap<String, Vec<Click>>,
+    seq: AtomicI64,
+}
+
+#[async_trait]
+impl UrlStore for MemoryStore {
+    fn name(&self) -> &'static str {
+        "memory"
+    }
+
+    async fn get_url(&self, id: &str) -> Result<UrlRecord, AppError> {
+        self.ids
+            .get(id)
+            .map(|record| record.value().clone())
+            .ok_or(AppError::IdNotFound)
+    }
+
+    async fn insert_url(&self, new: &NewUrl) -> Result<UrlRecord, AppError> {
+        let Some(key) = new.dedup_key() else {
+            return self.insert_id(new);
+        };
+
+        // always lock `dedup` before `ids` so concurrent inserts can't deadlock
+        match self.dedup.entry(key) {
+            Entry::Occupied(e) => self
+                .ids
+                .get(e.get())
+                .map(|record| record.value().clone())
+                .ok_or(AppError::IdNotFound),
+            Entry::Vacant(e) => {
+                let record = self.insert_id(new)?;
+                e.insert(record.id.clone());
+                Ok(record)
+            }
+        }
+    }
+
+    async fn next_seq(&self) -> Result<i64, AppError> {
+        Ok(self.seq.fetch_add(1, Ordering::Relaxed) + 1)
+    }
+
+    async fn take_visit(&self, id: &str, now: DateTime<Utc>) -> Result<bool, AppError> {
+        let Some(mut record) = self.ids.get_mut(id) else {
+            return Ok(false);
+        };
+        if record.is_expired(now) || record.is_exhausted() {
+            return Ok(false);
+        }
+        record.visits += 1;
+        Ok(true)
+    }
+
+    async fn purge_expired(&self, now: DateTime<Utc>) -> Result<u64, AppError> {
+        let before = self.ids.len();
+        // limited urls are never deduplicated, so `dedup` has nothing to clean up
+        self.ids
+            .retain(|_, record| !record.is_expired(now) && !record.is_exhausted());
+        self.clicks.retain(|id, _| self.ids.contains_key(id));
+        Ok((before - self.ids.len()) as u64)
+    }
+
+    async fn list_urls(
+        &self,
+        owner: Option<&str>,
+        after: Option<&str>,
+        limit: i64,
+    ) -> Result<Vec<UrlRecord>, AppError> {
+        let after = after.unwrap_or_default();
+        let mut records: Vec<_> = self
+            .ids
+            .iter()
+            .filter(|r| {
+                owner.is_none_or(|o| r.owner.as_deref() == Some(o)) && r.id.as_str() > after
+            })
+            .map(|r| r.value().clone())
+            .collect();
+        records.sort_by(|a, b| a.id.cmp(&b.id));
+        records.truncate(limit.max(0) as usize);
+        Ok(records)
+    }
+
+    async fn update_url(&self, owner: &str, id: &str, url: &str) -> Result<UrlRecord, AppError> {
+        if !self.is_owned(owner, id) {
+            return Err(AppError::IdNotFound);
+        }
+        self.dedup.retain(|_, v| v != id);
+
+        let mut record = self.ids.get_mut(id).ok_or(AppError::IdNotFound)?;
+        record.url = url.to_string();
+        Ok(record.clone())
+    }
+
+    async fn delete_url(&self, owner: Option<&str>, id: &str) -> Result<(), AppError> {
+        let found = match owner {
+            Some(owner) => self.is_owned(owner, id),
+            None => self.ids.contains_key(id),
+        };
+        if !found {
+            return Err(AppError::IdNotFound);
+        }
+        self.dedup.retain(|_, v| v != id);
+        self.ids.remove(id);
+        self.clicks.remove(id);
+        Ok(())
+    }
+
+    async fn count_clicks(&self, id: &str) -> Result<i64, AppError> {
+        Ok(self
+            .clicks
+            .get(id)
+            .map(|c| c.len() as i64)
+            .unwrap_or_default())
+    }
+
+    async fn record_click(&self, click: &Click) -> Result<(), AppError> {
+        if !self.ids.contains_key(&click.id) {
+            return Err(AppError::IdNotFound);
+        }
+        self.clicks
+            .entry(click.id.clone())
+            .or_default()
+            .push(click.clone());
+        Ok(())
+    }
+
+    async fn stats(
+        &self,
+        id: &str,
+        since: DateTime<Utc>,
+        top_referrers: i64,
+    ) -> Result<Stats, AppError> {
+        let mut daily = BTreeMap::new();
+        let mut referrers = HashMap::new();
+        let mut total = 0;
+        if let Some(clicks) = self.clicks.get(id) {
+            for click in clicks.iter() {
+                total += 1;
+                if click.clicked_at >= since {
+                    *daily.entry(click.clicked_at.date_naive()).or_insert(0) += 1;
+                }
+                if let Some(referer) = &click.referer {
+                    *referrers.entry(referer.clone()).or_insert(0) += 1;
+                }
+            }
+        }
+
+        let mut top: Vec<_> = referrers
+            .into_iter()
+            .map(|(referer, clicks)| RefererClicks { referer, clicks })
+            .collect();
+        top.sort_by(|a, b| b.clicks.cmp(&a.clicks).then(a.referer.cmp(&b.referer)));
+        top.truncate(top_referrers.max(0) as usize);
+
+        Ok(Stats {
+            id: id.to_string(),
+            total,
+            daily: daily
+                .into_iter()
+                .map(|(day, clicks)| DailyClicks { day, clicks })
+                .collect(),
+            top_referrers: top,
+        })
+    }
+}
+
+impl MemoryStore {
+    fn is_owned(&self, owner: &str, id: &str) -> bool {
+        self.ids
+            .get(id)
+            .is_some_and(|r| r.owner.as_deref() == Some(owner))
+    }
+
+    fn insert_id(&self, new: &NewUrl) -> Result<UrlRecord, AppError> {
+        match self.ids.entry(new.id.clone()) {
+            Entry::Occupied(_) => Err(AppError::IdExists),
+            Entry::Vacant(e) => {
+                let record = UrlRecord {
+                    id: new.id.clone(),
+                    url: new.url.clone(),
+                    owner: Some(new.owner.clone()),
+                    created_at: Utc::now(),
+                    expires_at: new.limits.expires_at,
+                    max_visits: new.limits.max_visits,
+                    visits: 0,
+                    interstitial: new.interstitial,
+                    inserted: false,
+                };
+                e.insert(record.clone());
+                Ok(UrlRecord {
+                    inserted: true,
+                    ..record
+                })
+            }
+        }
+    }
+}
@@ -0,0 +1,563 @@
+mod memory;
+mod pg;
+mod sqlite;
+
+pub use memory::MemoryStore;
+pub use pg::PgStore;
+pub use sqlite::SqliteStore;
+
+use crate::error::AppError;
+use anyhow::{bail, Result};
+use async_trait::async_trait;
+use chrono::{DateTime, NaiveDate, Utc};
+use serde::Serialize;
+use sqlx::migrate::{Migrate, Migrator};
+use sqlx::FromRow;
+use std::collections::HashSet;
+use std::sync::Arc;
+
+#[derive(Debug, Clone, Default, Serialize, FromRow)]
+pub struct UrlRecord {
+    #[sqlx(default)]
+    pub id: String,
+    #[sqlx(default)]
+    pub url: String,
+    #[sqlx(default)]
+    pub owner: Option<String>,
+    #[sqlx(default)]
+    pub created_at: DateTime<Utc>,
+    #[sqlx(default)]
+    pub expires_at: Option<DateTime<Utc>>,
+    #[sqlx(default)]
+    pub max_visits: Option<i64>,
+    #[sqlx(default)]
+    pub visits: i64,
+    /// Show a confirmation page before redirecting.
+    #[sqlx(default)]
+    pub interstitial: bool,
+    /// Set by `insert_url` when it created the record rather than returning an existing one.
+    #[serde(skip)]
+    #[sqlx(default)]
+    pub inserted: bool,
+}
+
+/// Connection pool usage of a db backed store.
+#[derive(Debug, Clone, Copy)]
+pub struct PoolStats {
+    pub connections: u32,
+    pub idle: usize,
+    pub max: u32,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Limits {
+    pub expires_at: Option<DateTime<Utc>>,
+    pub max_visits: Option<i64>,
+}
+
+#[derive(Debug, Clone)]
+pub struct NewUrl {
+    pub id: String,
+    pub url: String,
+    pub owner: String,
+    /// User-chosen ids (aliases) are never deduplicated against existing urls.
+    pub custom: bool,
+    pub limits: Limits,
+    pub interstitial: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct Click {
+    pub id: String,
+    pub clicked_at: DateTime<Utc>,
+    pub referer: Option<String>,
+    pub user_agent: Option<String>,
+    pub ip: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Stats {
+    pub id: String,
+    pub total: i64,
+    pub daily: Vec<DailyClicks>,
+    pub top_referrers: Vec<RefererClicks>,
+}
+
+#[derive(Debug, Clone, Serialize, FromRow)]
+pub struct DailyClicks {
+    pub day: NaiveDate,
+    pub clicks: i64,
+}
+
+#[derive(Debug, Clone, Serialize, FromRow)]
+pub struct RefererClicks {
+    pub referer: String,
+    pub clicks: i64,
+}
+
+impl UrlRecord {
+    pub fn is_expired(&self, now: DateTime<Utc>) -> bool {
+        self.expires_at.is_some_and(|t| t <= now)
+    }
+
+    pub fn is_exhausted(&self) -> bool {
+        self.max_visits.is_some_and(|n| self.visits >= n)
+    }
+}
+
+impl NewUrl {
+    /// Generated ids without limits or interstitial share a single record per owner and url,
+    /// everything else gets its own.
+    pub fn dedup_key(&self) -> Option<String> {
+        let limited = self.limits.expires_at.is_some() || self.limits.max_visits.is_some();
+        (!self.custom && !limited && !self.interstitial)
+            .then(|| format!("{}:{}", self.owner, self.url))
+    }
+}
+
+#[async_trait]
+pub trait UrlStore: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    /// Applies pending schema migrations, returns the ones applied by this call.
+    async fn migrate(&self) -> Result<Vec<String>> {
+        Ok(vec![])
+    }
+
+    /// Checks that the store can serve queries.
+    async fn ping(&self) -> Result<(), AppError> {
+        Ok(())
+    }
+
+    fn pool_stats(&self) -> Option<PoolStats> {
+        None
+    }
+
+    async fn get_url(&self, id: &str) -> Result<UrlRecord, AppError>;
+
+    /// Stores `new.url` under `new.id`. A url that was already shortened with a generated id
+    /// keeps that id, and `AppError::IdExists` is returned when `new.id` is taken.
+    async fn insert_url(&self, new: &NewUrl) -> Result<UrlRecord, AppError>;
+
+    /// Inserts a batch like `insert_url`, with one result per item. Database backends do it in
+    /// a single transaction where a failing item is rolled back on its own.
+    async fn insert_urls(
+        &self,
+        news: &[NewUrl],
+    ) -> Result<Vec<Result<UrlRecord, AppError>>, AppError> {
+        let mut results = Vec::with_capacity(news.len());
+        for new in news {
+            match self.insert_url(new).await {
+                Err(AppError::DbUnavailable) => return Err(AppError::DbUnavailable),
+                ret => results.push(ret),
+            }
+        }
+        Ok(results)
+    }
+
+    /// The next value of a counter shared by every instance, for integer based ids.
+    async fn next_seq(&self) -> Result<i64, AppError>;
+
+    /// Counts one visit, returns false once the url is expired or out of visits.
+    async fn take_visit(&self, id: &str, now: DateTime<Utc>) -> Result<bool, AppError>;
+
+    /// Deletes urls that are expired or out of visits, returns how many were removed.
+    async fn purge_expired(&self, now: DateTime<Utc>) -> Result<u64, AppError>;
+
+    /// A page of `owner`'s urls ordered by id, starting after the id `after`. `None` lists
+    /// the urls of every owner, for operators.
+    async fn list_urls(
+        &self,
+        owner: Option<&str>,
+        after: Option<&str>,
+        limit: i64,
+    ) -> Result<Vec<UrlRecord>, AppError>;
+
+    /// Points `id` at a new url. The url stops being reused by later shorten requests.
+    async fn update_url(&self, owner: &str, id: &str, url: &str) -> Result<UrlRecord, AppError>;
+
+    /// Deletes `id` if it belongs to `owner`, or whoever it belongs to with `None`.
+    async fn delete_url(&self, owner: Option<&str>, id: &str) -> Result<(), AppError>;
+
+    async fn count_clicks(&self, id: &str) -> Result<i64, AppError>;
+
+    async fn record_click(&self, click: &Click) -> Result<(), AppError>;
+
+    /// Aggregates the clicks of `id`, with daily buckets starting at `since`.
+    async fn stats(
+        &self,
+        id: &str,
+        since: DateTime<Utc>,
+        top_referrers: i64,
+    ) -> Result<Stats, AppError>;
+}
+
+/// Picks the backend from the scheme of `db_url`: `postgres://`, `sqlite:` or `memory://`.
+pub async fn connect(db_url: &str, pool_size: u32) -> Result<Arc<dyn UrlStore>> {
+    let store: Arc<dyn UrlStore> = match db_url.split_once(':') {
+        Some(("postgres" | "postgresql", _)) => {
+            Arc::new(PgStore::try_new(db_url, pool_size).await?)
+        }
+        Some(("sqlite", _)) => Arc::new(SqliteStore::try_new(db_url, pool_size).await?),
+        Some(("memory", _)) => Arc::new(MemoryStore::default()),
+        _ => bail!("unsupported db url: {}", db_url),
+    };
+    Ok(store)
+}
+
+async fn applied_migrations(conn: &mut impl Migrate) -> Result<HashSet<i64>> {
+    conn.ensure_migrations_table().await?;
+    let applied = conn
+        .list_applied_migrations()
+        .await?
+        .into_iter()
+        .map(|m| m.version)
+        .collect();
+    Ok(applied)
+}
+
+fn newly_applied(migrator: &Migrator, before: &HashSet<i64>) -> Vec<String> {
+    migrator
+        .iter()
+        .filter(|m| !before.contains(&m.version))
+        .map(|m| format!("{} {}", m.version, m.description))
+        .collect()
+}
+
+/// Implements `UrlStore` for a sqlx backed store with a `db` pool. Only the dialect specific
+/// sql is passed in: the next value of the id counter, the utc day of `clicked_at`, and whether
+/// a row returned by the insert was created rather than deduplicated.
+macro_rules! sql_store {
+    (
+        $store:ident,
+        name: $name:literal,
+        executor: $executor:ident,
+        migrator: $migrator:ident,
+        next_seq: $next_seq:literal,
+        day: $day:literal,
+        inserted: $inserted:literal $(,)?
+    ) => {
+        #[async_trait::async_trait]
+        impl $crate::store::UrlStore for $store {
+            fn name(&self) -> &'static str {
+                $name
+            }
+
+            async fn migrate(&self) -> anyhow::Result<Vec<String>> {
+                let applied =
+                    $crate::store::applied_migrations(&mut *self.db.acquire().await?).await?;
+                $migrator.run(&self.db).await?;
+                Ok($crate::store::newly_applied(&$migrator, &applied))
+            }
+
+            async fn ping(&self) -> Result<(), $crate::error::AppError> {
+                sqlx::query("SELECT 1").execute(&self.db).await?;
+                Ok(())
+            }
+
+            fn pool_stats(&self) -> Option<$crate::store::PoolStats> {
+                Some($crate::store::PoolStats {
+                    connections: self.db.size(),
+                    idle: self.db.num_idle(),
+                    max: self.db.options().get_max_connections(),
+                })
+            }
+
+            async fn get_url(
+                &self,
+                id: &str,
+            ) -> Result<$crate::store::UrlRecord, $crate::error::AppError> {
+                let record: $crate::store::UrlRecord = sqlx::query_as(
+                    r#"SELECT id, url, owner, created_at, expires_at, max_visits, visits, interstitial
+                        FROM urls WHERE id = $1"#,
+                )
+                .bind(id)
+                .fetch_one(&self.db)
+                .await?;
+
+                Ok(record)
+            }
+
+            async fn insert_url(
+                &self,
+                new: &$crate::store::NewUrl,
+            ) -> Result<$crate::store::UrlRecord, $crate::error::AppError> {
+                insert(&self.db, new).await
+            }
+
+            async fn insert_urls(
+                &self,
+                news: &[$crate::store::NewUrl],
+            ) -> Result<
+                Vec<Result<$crate::store::UrlRecord, $crate::error::AppError>>,
+                $crate::error::AppError,
+            > {
+                use sqlx::Connection;
+                use $crate::error::AppError;
+
+                let mut tx = self.db.begin().await?;
+                let mut results = Vec::with_capacity(news.len());
+                for new in news {
+                    // a nested transaction is a savepoint, so one bad item doesn't abort the rest
+                    let mut item = tx.begin().await?;
+                    match insert(&mut *item, new).await {
+                        Ok(record) => {
+                            item.commit().await?;
+                            results.push(Ok(record));
+                        }
+                        Err(AppError::DbUnavailable) => return Err(AppError::DbUnavailable),
+                        Err(e) => {
+                            item.rollback().await?;
+                            results.push(Err(e));
+                        }
+                    }
+                }
+                tx.commit().await?;
+
+                Ok(results)
+            }
+
+            async fn next_seq(&self) -> Result<i64, $crate::error::AppError> {
+                let n: i64 = sqlx::query_scalar($next_seq).fetch_one(&self.db).await?;
+
+                Ok(n)
+            }
+
+            async fn take_visit(
+                &self,
+                id: &str,
+                now: chrono::DateTime<chrono::Utc>,
+            ) -> Result<bool, $crate::error::AppError> {
+                let ret = sqlx::query(
+                    r#"UPDATE urls SET visits = visits + 1
+                        WHERE id = $1
+                          AND (max_visits IS NULL OR visits < max_visits)
+                          AND (expires_at IS NULL OR expires_at > $2)"#,
+                )
+                .bind(id)
+                .bind(now)
+                .execute(&self.db)
+                .await?;
+
+                Ok(ret.rows_affected() == 1)
+            }
+
+            async fn purge_expired(
+                &self,
+                now: chrono::DateTime<chrono::Utc>,
+            ) -> Result<u64, $crate::error::AppError> {
+                let ret = sqlx::query(
+                    "DELETE FROM urls WHERE expires_at <= $1 OR visits >= max_visits",
+                )
+                .bind(now)
+                .execute(&self.db)
+                .await?;
+
+                Ok(ret.rows_affected())
+            }
+
+            async fn list_urls(
+                &self,
+                owner: Option<&str>,
+                after: Option<&str>,
+                limit: i64,
+            ) -> Result<Vec<$crate::store::UrlRecord>, $crate::error::AppError> {
+                // the cast gives postgres a type for a null `owner`
+                let records: Vec<$crate::store::UrlRecord> = sqlx::query_as(
+                    r#"SELECT id, url, owner, created_at, expires_at, max_visits, visits, interstitial
+                        FROM urls WHERE (CAST($1 AS TEXT) IS NULL OR owner = $1) AND id > $2
+                        ORDER BY id LIMIT $3"#,
+                )
+                .bind(owner)
+                .bind(after.unwrap_or_default())
+                .bind(limit)
+                .fetch_all(&self.db)
+                .await?;
+
+                Ok(records)
+            }
+
+            async fn update_url(
+                &self,
+                owner: &str,
+                id: &str,
+                url: &str,
+            ) -> Result<$crate::store::UrlRecord, $crate::error::AppError> {
+                let record: $crate::store::UrlRecord = sqlx::query_as(
+                    r#"UPDATE urls SET url = $3, dedup_key = NULL
+                        WHERE id = $1 AND owner = $2
+                        RETURNING id, url, owner, created_at, expires_at, max_visits, visits, interstitial"#,
+                )
+                .bind(id)
+                .bind(owner)
+                .bind(url)
+                .fetch_one(&self.db)
+                .await?;
+
+                Ok(record)
+            }
+
+            async fn delete_url(
+                &self,
+                owner: Option<&str>,
+                id: &str,
+            ) -> Result<(), $crate::error::AppError> {
+                let ret = sqlx::query(
+                    "DELETE FROM urls WHERE id = $1 AND (CAST($2 AS TEXT) IS NULL OR owner = $2)",
+                )
+                .bind(id)
+                .bind(owner)
+                .execute(&self.db)
+                .await?;
+
+                match ret.rows_affected() {
+                    0 => Err($crate::error::AppError::IdNotFound),
+                    _ => Ok(()),
+                }
+            }
+
+            async fn count_clicks(&self, id: &str) -> Result<i64, $crate::error::AppError> {
+                let n: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM clicks WHERE url_id = $1")
+                    .bind(id)
+                    .fetch_one(&self.db)
+                    .await?;
+
+                Ok(n)
+            }
+
+            async fn record_click(
+                &self,
+                click: &$crate::store::Click,
+            ) -> Result<(), $crate::error::AppError> {
+                sqlx::query(
+                    r#"INSERT INTO clicks (url_id, clicked_at, referer, user_agent, ip)
+                        VALUES ($1, $2, $3, $4, $5)"#,
+                )
+                .bind(&click.id)
+                .bind(click.clicked_at)
+                .bind(&click.referer)
+                .bind(&click.user_agent)
+                .bind(&click.ip)
+                .execute(&self.db)
+                .await?;
+
+                Ok(())
+            }
+
+            async fn stats(
+                &self,
+                id: &str,
+                since: chrono::DateTime<chrono::Utc>,
+                top_referrers: i64,
+            ) -> Result<$crate::store::Stats, $crate::error::AppError> {
+                let total = self.count_clicks(id).await?;
+
+                let daily: Vec<$crate::store::DailyClicks> = sqlx::query_as(concat!(
+                    "SELECT ",
+                    $day,
+                    r#" AS day, COUNT(*) AS clicks FROM clicks
+                        WHERE url_id = $1 AND clicked_at >= $2
+                        GROUP BY day ORDER BY day"#,
+                ))
+                .bind(id)
+                .bind(since)
+                .fetch_all(&self.db)
+                .await?;
+
+                let top_referrers: Vec<$crate::store::RefererClicks> = sqlx::query_as(
+                    r#"SELECT referer, COUNT(*) AS clicks FROM clicks
+                        WHERE url_id = $1 AND referer IS NOT NULL
+                        GROUP BY referer ORDER BY clicks DESC, referer LIMIT $2"#,
+                )
+                .bind(id)
+                .bind(top_referrers)
+                .fetch_all(&self.db)
+                .await?;
+
+                Ok($crate::store::Stats {
+                    id: id.to_string(),
+                    total,
+                    daily,
+                    top_referrers,
+                })
+            }
+        }
+
+        async fn insert(
+            db: impl $executor<'_>,
+            new: &$crate::store::NewUrl,
+        ) -> Result<$crate::store::UrlRecord, $crate::error::AppError> {
+            let record: $crate::store::UrlRecord = sqlx::query_as(concat!(
+                r#"INSERT INTO urls (id, url, owner, created_at, dedup_key, expires_at, max_visits, interstitial)
+                    VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
+                    ON CONFLICT(dedup_key) DO UPDATE SET dedup_key = excluded.dedup_key
+                    RETURNING id, url, owner, created_at, expires_at, max_visits, visits, interstitial,
+                        "#,
+                $inserted,
+                " AS inserted",
+            ))
+            .bind(&new.id)
+            .bind(&new.url)
+            .bind(&new.owner)
+            .bind(chrono::Utc::now())
+            .bind(new.dedup_key())
+            .bind(new.limits.expires_at)
+            .bind(new.limits.max_visits)
+            .bind(new.interstitial)
+            .fetch_one(db)
+            .await?;
+
+            Ok(record)
+        }
+    };
+}
+
+pub(crate) use sql_store;
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    pub fn new_url(id: &str, limits: Limits) -> NewUrl {
+        NewUrl {
+            id: id.into(),
+            url: format!("https://www.rust-lang.org/{}", id),
+            owner: "alice".into(),
+            custom: true,
+            limits,
+            interstitial: false,
+        }
+    }
+
+    /// Runs against any backend: only expired and exhausted urls are purged.
+    pub async fn purge_should_keep_live_urls(store: &dyn UrlStore) {
+        let now = Utc::now();
+        let limits = |expires_in: Option<i64>, max_visits| Limits {
+            expires_at: expires_in.map(|secs| now + chrono::Duration::seconds(secs)),
+            max_visits,
+        };
+        for (id, limits) in [
+            ("live", limits(Some(60), Some(2))),
+            ("forever", limits(None, None)),
+            ("expired", limits(Some(-1), None)),
+            ("used", limits(None, Some(1))),
+        ] {
+            store.insert_url(&new_url(id, limits)).await.unwrap();
+        }
+        assert!(store.take_visit("used", now).await.unwrap());
+        assert!(store.take_visit("live", now).await.unwrap());
+
+        assert_eq!(store.purge_expired(now).await.unwrap(), 2);
+        for id in ["live", "forever"] {
+            assert_eq!(store.get_url(id).await.unwrap().id, id);
+        }
+        for id in ["expired", "used"] {
+            assert!(matches!(store.get_url(id).await, Err(AppError::IdNotFound)));
+        }
+    }
+
+    #[tokio::test]
+    async fn memory_store_should_purge_expired() {
+        purge_should_keep_live_urls(&MemoryStore::default()).await;
+    }
+}
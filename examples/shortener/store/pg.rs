@@ -0,0 +1,33 @@
+use super::sql_store;
+use anyhow::Result;
+use sqlx::migrate::Migrator;
+use sqlx::postgres::PgPoolOptions;
+use sqlx::{PgExecutor, PgPool};
+
+static MIGRATOR: Migrator = sqlx::migrate!("examples/shortener/migrations/postgres");
+
+pub struct PgStore {
+    db: PgPool,
+}
+
+impl PgStore {
+    pub async fn try_new(db_url: &str, pool_size: u32) -> Result<Self> {
+        let pool = PgPoolOptions::new()
+            .max_connections(pool_size)
+            .connect(db_url)
+            .await?;
+
+        Ok(Self { db: pool })
+    }
+}
+
+sql_store! {
+    PgStore,
+    name: "postgres",
+    executor: PgExecutor,
+    migrator: MIGRATOR,
+    next_seq: "SELECT nextval('url_seq')",
+    day: "(clicked_at AT TIME ZONE 'UTC')::date",
+    // only rows created by this statement have no locking transaction yet
+    inserted: "(xmax = 0)",
+}
@@ -0,0 +1,113 @@
+use super::sql_store;
+use anyhow::Result;
+use sqlx::migrate::Migrator;
+use sqlx::sqlite::{SqliteConnectOptions, SqlitePoolOptions};
+use sqlx::{SqliteExecutor, SqlitePool};
+use std::str::FromStr;
+
+static MIGRATOR: Migrator = sqlx::migrate!("examples/shortener/migrations/sqlite");
+
+pub struct SqliteStore {
+    db: SqlitePool,
+}
+
+impl SqliteStore {
+    pub async fn try_new(db_url: &str, pool_size: u32) -> Result<Self> {
+        let options = SqliteConnectOptions::from_str(db_url)?.create_if_missing(true);
+        let pool = SqlitePoolOptions::new()
+            .max_connections(pool_size)
+            .connect_with(options)
+            .await?;
+
+        Ok(Self { db: pool })
+    }
+}
+
+sql_store! {
+    SqliteStore,
+    name: "sqlite",
+    executor: SqliteExecutor,
+    migrator: MIGRATOR,
+    next_seq: "UPDATE url_seq SET value = value + 1 RETURNING value",
+    day: "date(clicked_at)",
+    // a deduplicated row keeps the created_at of its first insert
+    inserted: "created_at = $4",
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::AppConfig;
+    use crate::store::tests::purge_should_keep_live_urls;
+    use crate::store::UrlStore;
+    use crate::test_util::*;
+    use axum::body::Body;
+    use chrono::Utc;
+    use http::StatusCode;
+    use std::sync::Arc;
+
+    // every connection to `sqlite::memory:` has its own db, so keep a single one
+    const DB_URL: &str = "sqlite::memory:";
+
+    async fn test_store() -> SqliteStore {
+        let store = SqliteStore::try_new(DB_URL, 1).await.unwrap();
+        store.migrate().await.unwrap();
+        store
+    }
+
+    #[tokio::test]
+    async fn migrate_should_be_idempotent() {
+        let store = SqliteStore::try_new(DB_URL, 1).await.unwrap();
+        let applied = store.migrate().await.unwrap();
+        assert_eq!(applied.len(), MIGRATOR.iter().count());
+        assert!(store.migrate().await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn sqlite_store_should_purge_expired() {
+        purge_should_keep_live_urls(&test_store().await).await;
+    }
+
+    #[tokio::test]
+    async fn shorten_redirect_and_stats_should_work() {
+        let state = Arc::new(
+            test_state_with(AppConfig {
+                db_url: DB_URL.into(),
+                pool_size: 1,
+                ..Default::default()
+            })
+            .await,
+        );
+        let app = crate::app(Arc::clone(&state));
+
+        let id = shorten_url(&app, "https://www.rust-lang.org/").await;
+        assert_eq!(shorten_url(&app, "https://www.rust-lang.org/").await, id);
+        let body =
+            serde_json::json!({ "url": "https://docs.rs/", "alias": "twice", "max_visits": 2 });
+        assert_eq!(post_shorten(&app, body).await.status(), StatusCode::CREATED);
+
+        for _ in 0..2 {
+            let res = fetch(&app, &format!("/{}", id)).await;
+            assert_eq!(res.status(), StatusCode::PERMANENT_REDIRECT);
+        }
+        for status in [
+            StatusCode::PERMANENT_REDIRECT,
+            StatusCode::PERMANENT_REDIRECT,
+            StatusCode::GONE,
+        ] {
+            assert_eq!(fetch(&app, "/twice").await.status(), status);
+        }
+        assert_eq!(state.store.get_url("twice").await.unwrap().visits, 2);
+        // the dedup hit isn't counted
+        assert_eq!(state.metrics.shortened.get(), 2);
+
+        wait_for_clicks(&state, &id, 2).await;
+        let uri = format!("/{}/stats", id);
+        let stats = body_json(send(&app, "GET", &uri, ALICE_KEY, Body::empty()).await).await;
+        assert_eq!(stats["total"], 2);
+        assert_eq!(
+            stats["daily"],
+            serde_json::json!([{ "day": Utc::now().date_naive(), "clicks": 2 }])
+        );
+    }
+}
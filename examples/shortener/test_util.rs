@@ -0,0 +1,102 @@
+//! Helpers shared by the tests of every module.
+
+use crate::config::AppConfig;
+use crate::{app, AppState, ShortenRes};
+use axum::body::Body;
+use axum::response::Response;
+use axum::Router;
+use http::{Request, StatusCode};
+use std::sync::Arc;
+use std::time::Duration;
+use tower::ServiceExt;
+
+pub const ALICE_KEY: &str = "alice-secret";
+pub const BOB_KEY: &str = "bob-secret";
+
+/// The default config on the memory store.
+pub fn test_config() -> AppConfig {
+    AppConfig {
+        db_url: "memory://".into(),
+        ..Default::default()
+    }
+}
+
+pub async fn test_state() -> AppState {
+    test_state_with(test_config()).await
+}
+
+/// An app where `ALICE_KEY` belongs to alice and `BOB_KEY` to bob.
+pub async fn test_state_with(config: AppConfig) -> AppState {
+    let api_keys = format!(
+        "alice:{},bob:{}",
+        blake3::hash(ALICE_KEY.as_bytes()),
+        blake3::hash(BOB_KEY.as_bytes())
+    );
+    let config = AppConfig { api_keys, ..config };
+    AppState::try_new(config).await.unwrap()
+}
+
+pub async fn test_app() -> Router {
+    test_app_with(test_config()).await
+}
+
+pub async fn test_app_with(config: AppConfig) -> Router {
+    app(Arc::new(test_state_with(config).await))
+}
+
+pub async fn fetch(app: &Router, uri: &str) -> Response {
+    let req = Request::get(uri).body(Body::empty()).unwrap();
+    app.clone().oneshot(req).await.unwrap()
+}
+
+pub async fn send(app: &Router, method: &str, uri: &str, key: &str, body: Body) -> Response {
+    let req = Request::builder()
+        .method(method)
+        .uri(uri)
+        .header("authorization", format!("Bearer {}", key))
+        .header("content-type", "application/json")
+        .body(body)
+        .unwrap();
+    app.clone().oneshot(req).await.unwrap()
+}
+
+pub async fn post_shorten(app: &Router, body: serde_json::Value) -> Response {
+    send(app, "POST", "/", ALICE_KEY, Body::from(body.to_string())).await
+}
+
+/// Shortens `url` as alice, returns the id.
+pub async fn shorten_url(app: &Router, url: &str) -> String {
+    let res = post_shorten(app, serde_json::json!({ "url": url })).await;
+    assert_eq!(res.status(), StatusCode::CREATED);
+
+    let res: ShortenRes = serde_json::from_slice(&body_bytes(res).await).unwrap();
+    res.url
+        .trim_start_matches(AppConfig::default().base_url.as_str())
+        .to_string()
+}
+
+/// Waits for the click recorder to catch up with `n` clicks on `id`.
+pub async fn wait_for_clicks(state: &AppState, id: &str, n: i64) {
+    for _ in 0..100 {
+        if state.store.count_clicks(id).await.unwrap() >= n {
+            return;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    panic!("{} never got {} clicks", id, n);
+}
+
+pub async fn body_bytes(res: Response) -> Vec<u8> {
+    axum::body::to_bytes(res.into_body(), usize::MAX)
+        .await
+        .unwrap()
+        .to_vec()
+}
+
+pub async fn body_string(res: Response) -> String {
+    String::from_utf8(body_bytes(res).await).unwrap()
+}
+
+pub async fn body_json(res: Response) -> serde_json::Value {
+    serde_json::from_slice(&body_bytes(res).await).unwrap()
+}